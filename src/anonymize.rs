@@ -0,0 +1,479 @@
+use std::env;
+
+use rand::{thread_rng, Rng};
+use serde_json::{Map, Value};
+
+use jws::{read_secret, Key};
+use timestamp;
+use tz::HRIS_TIMEZONES;
+
+/// Environment variable holding the anonymization secret if no file is given.
+pub const SECRET_ENV: &str = "V2CONV_ANONYMIZE_SECRET";
+
+pub const FIRST_NAMES: &[&str] = &[
+    "Alex", "Sam", "Robin", "Kim", "Charlie", "Jamie", "Taylor", "Jordan", "Morgan", "Casey",
+    "Riley", "Avery", "Quinn", "Drew", "Sky", "Rowan",
+];
+pub const LAST_NAMES: &[&str] = &[
+    "Smith", "Jones", "Miller", "Garcia", "Kowalski", "Tanaka", "Novak", "Rossi", "Dubois",
+    "Nielsen", "Silva", "Okafor", "Larsen", "Haddad", "Ivanova", "Meyer",
+];
+pub const TITLES: &[&str] = &[
+    "Software Engineer",
+    "Product Manager",
+    "Program Manager",
+    "Data Scientist",
+    "Designer",
+    "Security Engineer",
+    "Community Manager",
+    "Release Engineer",
+];
+pub const LOCATIONS: &[&str] = &["Berlin", "Mountain View", "Toronto", "Paris", "Remote"];
+/// Email domains kept as they are since `load_ldap` depends on them.
+const DOMAINS: &[&str] = &["mozilla.com", "mozillafoundation.org", "getpocket.com"];
+/// HRIS columns without personal data.
+const HRIS_KEEP: &[&str] = &[
+    "Cost_Center",
+    "CurrentlyActive",
+    "IsManager",
+    "Team",
+    "Time_Zone",
+    "WorkerType",
+    "isDirectorOrAbove",
+];
+/// LDAP attributes `ldap` knows how to anonymize. Everything else is dropped.
+const LDAP_KEEP: &[&str] = &[
+    "access_information",
+    "active",
+    "description",
+    "first_name",
+    "fun_title",
+    "identities",
+    "last_name",
+    "login_method",
+    "pgp_public_keys",
+    "phone_numbers",
+    "picture",
+    "primary_email",
+    "ssh_public_keys",
+    "user_id",
+    "usernames",
+];
+/// Mozillians fields `mozillians` knows how to anonymize. Everything else is
+/// dropped.
+const MOZILLIANS_KEEP: &[&str] = &[
+    "access_information",
+    "date_joined",
+    "description",
+    "first_name",
+    "fun_title",
+    "idps",
+    "last_name",
+    "location_preference",
+    "picture",
+    "preferred_language",
+    "skills",
+    "tags",
+    "timezone",
+    "uris",
+    "user_id",
+    "username",
+];
+
+/// Replaces personal data with fake values derived from a keyed hash of the
+/// original, so the same person stays joinable across sources.
+pub struct Anonymizer {
+    key: Key,
+}
+
+impl Anonymizer {
+    pub fn new(secret: &[u8]) -> Self {
+        Anonymizer {
+            key: Key::Hmac(secret.to_vec()),
+        }
+    }
+
+    /// Reads the secret from `path` or, if not given, from
+    /// `V2CONV_ANONYMIZE_SECRET`. Without either the secret is random and
+    /// every run gives different fake values.
+    pub fn load(path: Option<&str>) -> Result<Self, String> {
+        let secret = if path.is_none() && env::var_os(SECRET_ENV).is_none() {
+            thread_rng().gen::<[u8; 32]>().to_vec()
+        } else {
+            read_secret(path, SECRET_ENV)?
+        };
+        Ok(Anonymizer::new(&secret))
+    }
+
+    fn digest(&self, kind: &str, value: &str) -> Vec<u8> {
+        self.key
+            .sign(format!("{}#{}", kind, value).as_bytes())
+            .expect("hmac")
+    }
+
+    fn pick(&self, kind: &str, value: &str, list: &[&str]) -> String {
+        let d = self.digest(kind, value);
+        String::from(list[usize::from(d[0]) % list.len()])
+    }
+
+    fn token(&self, kind: &str, value: &str, len: usize) -> String {
+        const CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+        self.digest(kind, value)
+            .iter()
+            .cycle()
+            .take(len)
+            .map(|b| char::from(CHARS[usize::from(*b) % CHARS.len()]))
+            .collect()
+    }
+
+    pub fn first_name(&self, v: &str) -> String {
+        self.pick("first_name", v, FIRST_NAMES)
+    }
+
+    pub fn last_name(&self, v: &str) -> String {
+        self.pick("last_name", v, LAST_NAMES)
+    }
+
+    pub fn username(&self, v: &str) -> String {
+        format!("user-{}", self.token("username", &v.to_lowercase(), 8))
+    }
+
+    pub fn email(&self, v: &str) -> String {
+        let domain = v
+            .rsplit('@')
+            .next()
+            .filter(|d| DOMAINS.contains(d))
+            .unwrap_or("example.com");
+        format!("{}@{}", self.token("email", v, 12), domain)
+    }
+
+    /// Keeps the provider prefix of ids like `ad|Mozilla-LDAP|hans`.
+    pub fn user_id(&self, v: &str) -> String {
+        match v.rfind('|') {
+            Some(i) => format!("{}{}", &v[..=i], self.token("user_id", v, 12)),
+            None => self.token("user_id", v, 12),
+        }
+    }
+
+    /// Replaces every digit and keeps the formatting.
+    pub fn digits(&self, v: &str) -> String {
+        let d = self.digest("digits", v);
+        v.chars()
+            .zip(d.iter().cycle())
+            .map(|(c, b)| {
+                if c.is_ascii_digit() {
+                    char::from(b'0' + b % 10)
+                } else {
+                    c
+                }
+            })
+            .collect()
+    }
+
+    /// Keeps the level suffix (` Mgmt 3`, ` 2`) `censor_title` cares about.
+    pub fn title(&self, v: &str) -> String {
+        let words: Vec<&str> = v.split(' ').collect();
+        let suffix_len = match words.as_slice() {
+            [.., "Mgmt", n] if n.parse::<u8>().is_ok() => 2,
+            [.., n] if words.len() > 1 && n.parse::<u8>().is_ok() => 1,
+            _ => 0,
+        };
+        let mut title = self.pick("title", v, TITLES);
+        for w in &words[words.len() - suffix_len..] {
+            title.push(' ');
+            title.push_str(w);
+        }
+        title
+    }
+
+    /// Keeps the key type of OpenSSH keys.
+    pub fn key(&self, v: &str) -> String {
+        let mut parts = v.split_whitespace();
+        match (parts.next(), parts.next()) {
+            (Some(t), Some(k)) if t.starts_with("ssh-") || t.starts_with("ecdsa-") => {
+                format!("{} {} anon@example.com", t, self.token("key", k, k.len()))
+            }
+            _ => self.token("key", v, v.len().min(64)),
+        }
+    }
+
+    pub fn text(&self, v: &str) -> String {
+        format!("anon-{}", self.token("text", v, 8))
+    }
+
+    /// Only keeps the year, as `<year>-01-01`.
+    pub fn year(&self, v: &str) -> String {
+        match timestamp::parse(v) {
+            Some(t) => format!("{}-01-01", &t[..4]),
+            None => self.text(v),
+        }
+    }
+
+    pub fn location(&self, v: &str) -> String {
+        self.pick("location", v, LOCATIONS)
+    }
+
+    pub fn timezone(&self, v: &str) -> String {
+        let d = self.digest("timezone", v);
+        let (_, tz) = HRIS_TIMEZONES[usize::from(d[0]) % HRIS_TIMEZONES.len()];
+        String::from(tz.name())
+    }
+
+    /// Replaces the group names at `pointer` and drops whatever they map to.
+    fn groups(&self, v: &mut Value, pointer: &str) {
+        if let Some(Value::Object(o)) = v.pointer_mut(pointer) {
+            *o = o
+                .keys()
+                .map(|k| (format!("group-{}", self.token("group", k, 8)), Value::Null))
+                .collect();
+        }
+    }
+
+    pub fn hris(&self, hris: Value) -> Value {
+        let o = match hris {
+            Value::Object(o) => o,
+            v => return v,
+        };
+        let record: Map<String, Value> = o
+            .into_iter()
+            .filter_map(|(k, v)| {
+                let v = match (k.as_str(), v.as_str()) {
+                    (k, _) if HRIS_KEEP.contains(&k) => v,
+                    (_, None) => return None,
+                    ("PrimaryWorkEmail", Some(s)) => json!(self.email(s)),
+                    ("EmployeeID", Some(s)) => json!(self.digits(s)),
+                    ("WPRDeskNumber", Some(s)) => json!(self.digits(s)),
+                    ("businessTitle", Some(s)) => json!(self.title(s)),
+                    ("Hire_Date", Some(s)) => json!(self.year(s)),
+                    ("LocationDescription", Some(s)) => json!(self.location(s)),
+                    (_, Some(s)) => json!(self.text(s)),
+                };
+                Some((k, v))
+            })
+            .collect();
+        Value::Object(record)
+    }
+
+    pub fn ldap(&self, ldap: Value) -> Value {
+        let mut ldap = retain(ldap, LDAP_KEEP);
+        let email = ldap["primary_email"]["value"]
+            .as_str()
+            .map(String::from)
+            .unwrap_or_default();
+        self.replace(&mut ldap, "/primary_email/value", |s| self.email(s));
+        self.replace(&mut ldap, "/user_id/value", |s| self.user_id(s));
+        self.replace(&mut ldap, "/first_name/value", |s| self.first_name(s));
+        self.replace(&mut ldap, "/last_name/value", |s| self.last_name(s));
+        self.replace(&mut ldap, "/fun_title/value", |s| self.title(s));
+        self.replace(&mut ldap, "/description/value", |s| self.text(s));
+        self.replace(&mut ldap, "/picture/value", |s| {
+            let ext = s.rsplit('.').next().unwrap_or("jpg");
+            format!("/{}.{}", self.token("picture", &email, 12), ext)
+        });
+        self.replace_values(&mut ldap, "/ssh_public_keys/values", |s| self.key(s));
+        self.replace_values(&mut ldap, "/pgp_public_keys/values", |s| self.key(s));
+        self.replace_values(&mut ldap, "/phone_numbers/values", |s| self.digits(s));
+        self.replace_values(&mut ldap, "/usernames/values", |s| match s.rfind(' ') {
+            Some(i) => format!("{} {}", &s[..i], self.username(&s[i + 1..])),
+            None => self.username(s),
+        });
+        if let Some(Value::Object(identities)) = ldap.get_mut("identities") {
+            for (_, i) in identities.iter_mut() {
+                self.replace(i, "/value", |s| self.user_id(s));
+            }
+        }
+        self.groups(&mut ldap, "/access_information/ldap/values");
+        ldap
+    }
+
+    pub fn mozillians(&self, m: Value) -> Value {
+        let mut m = retain(m, MOZILLIANS_KEEP);
+        self.replace(&mut m, "/username", |s| self.username(s));
+        self.replace(&mut m, "/user_id", |s| self.user_id(s));
+        self.replace(&mut m, "/first_name", |s| self.first_name(s));
+        self.replace(&mut m, "/last_name", |s| self.last_name(s));
+        self.replace(&mut m, "/fun_title", |s| self.title(s));
+        self.replace(&mut m, "/description", |s| self.text(s));
+        self.replace(&mut m, "/date_joined", |s| self.year(s));
+        self.replace(&mut m, "/location_preference", |s| self.location(s));
+        self.replace(&mut m, "/timezone", |s| self.timezone(s));
+        self.groups(&mut m, "/access_information");
+        for k in &["tags", "skills"] {
+            if let Some(Value::Array(a)) = m.get_mut(*k) {
+                for v in a {
+                    self.replace(v, "", |s| self.text(s));
+                }
+            }
+        }
+        if let Some(picture) = m.get_mut("picture") {
+            *picture = Value::Null;
+        }
+        self.replace_values(&mut m, "/uris", |s| {
+            if s.is_empty() {
+                String::new()
+            } else {
+                format!("https://example.com/{}", self.token("uri", s, 8))
+            }
+        });
+        if let Some(Value::Array(idps)) = m.get_mut("idps") {
+            for idp in idps.iter_mut() {
+                *idp = retain(idp.take(), &["email"]);
+                self.replace(idp, "/email", |s| self.email(s));
+            }
+        }
+        m
+    }
+
+    /// Replaces the string at `pointer` with `f` of it. Anything else but a
+    /// string is dropped.
+    fn replace<F: Fn(&str) -> String>(&self, v: &mut Value, pointer: &str, f: F) {
+        if let Some(v) = v.pointer_mut(pointer) {
+            *v = match v.as_str() {
+                Some(s) => json!(f(s)),
+                None => Value::Null,
+            };
+        }
+    }
+
+    fn replace_values<F: Fn(&str) -> String>(&self, v: &mut Value, pointer: &str, f: F) {
+        if let Some(Value::Object(o)) = v.pointer_mut(pointer) {
+            for (_, v) in o.iter_mut() {
+                self.replace(v, "", &f);
+            }
+        }
+    }
+}
+
+/// `v` with only the fields in `keep`.
+fn retain(v: Value, keep: &[&str]) -> Value {
+    match v {
+        Value::Object(o) => Value::Object(
+            o.into_iter()
+                .filter(|(k, _)| keep.contains(&k.as_str()))
+                .collect(),
+        ),
+        v => v,
+    }
+}
+
+/// Anonymizes all three sources. Fields `load_all` joins on are replaced
+/// consistently.
+pub fn anonymize_all(
+    a: &Anonymizer,
+    hris: Option<Value>,
+    ldap: Option<Value>,
+    mozillians: Option<Value>,
+) -> (Option<Value>, Option<Value>, Option<Value>) {
+    let hris = hris.map(|mut h| {
+        if let Some(entries) = h["Report_Entry"].as_array_mut() {
+            for e in entries.iter_mut() {
+                *e = a.hris(e.take());
+            }
+        }
+        h
+    });
+    let ldap = ldap.map(|l| match l {
+        Value::Object(o) => Value::Object(
+            o.into_iter()
+                .map(|(_, v)| {
+                    let v = a.ldap(v);
+                    let dn = format!(
+                        "mail={},o=com,dc=mozilla",
+                        v["primary_email"]["value"].as_str().unwrap_or_default()
+                    );
+                    (dn, v)
+                })
+                .collect(),
+        ),
+        l => l,
+    });
+    let mozillians = mozillians.map(|mut m| {
+        if let Some(entries) = m.as_array_mut() {
+            for e in entries.iter_mut() {
+                *e = a.mozillians(e.take());
+            }
+        }
+        m
+    });
+    (hris, ldap, mozillians)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_anonymize_all() {
+        let a = Anonymizer::new(b"test");
+        let hris = json!({ "Report_Entry": [{
+            "PrimaryWorkEmail": "hans@mozilla.com",
+            "businessTitle": "Staff Engineering Mgmt 3",
+            "PreferredName": "Hans Wurst",
+            "IsManager": "TRUE",
+            "Hire_Date": "2016-04-04",
+            "WPRDeskNumber": "1234",
+            "Salary": 12345
+        }]});
+        let ldap = json!({ "mail=hans@mozilla.com,o=com,dc=mozilla": {
+            "primary_email": { "value": "hans@mozilla.com" },
+            "user_id": { "value": "ad|Mozilla-LDAP|hans" },
+            "first_name": { "value": "Hans" },
+            "phone_numbers": { "values": { "office": "+49 30 1234" } },
+            "usernames": { "values": { "LDAP-1": "IRC: hansw" } },
+            "access_information": { "ldap": { "values": { "team_hans": null } } },
+            "home_directory": { "value": "/home/hans" }
+        }});
+        let mozillians = json!([{
+            "username": "hansw",
+            "user_id": "ad|Mozilla-LDAP|hans",
+            "first_name": "Hans",
+            "idps": [{ "email": "hans@mozilla.com", "name": "Hans" }],
+            "timezone": "Europe/Berlin",
+            "location_preference": "Hansestadt",
+            "access_information": { "hans-fans": null },
+            "age": 42
+        }]);
+        let (hris, ldap, mozillians) = anonymize_all(&a, Some(hris), Some(ldap), Some(mozillians));
+        let (hris, ldap, mozillians) = (hris.unwrap(), ldap.unwrap(), mozillians.unwrap());
+        let all = format!("{}{}{}", hris, ldap, mozillians);
+        assert!(!all.to_lowercase().contains("hans"));
+
+        let h = &hris["Report_Entry"][0];
+        let (dn, l) = ldap.as_object().unwrap().iter().next().unwrap();
+        let m = &mozillians[0];
+        assert_eq!(h["PrimaryWorkEmail"], l["primary_email"]["value"]);
+        assert_eq!(m["idps"][0]["email"], l["primary_email"]["value"]);
+        assert!(dn.contains(l["primary_email"]["value"].as_str().unwrap()));
+        assert!(h["PrimaryWorkEmail"]
+            .as_str()
+            .unwrap()
+            .ends_with("@mozilla.com"));
+        assert_eq!(l["user_id"]["value"], m["user_id"]);
+        assert!(m["user_id"]
+            .as_str()
+            .unwrap()
+            .starts_with("ad|Mozilla-LDAP|"));
+        assert_eq!(l["first_name"]["value"], m["first_name"]);
+        assert_eq!(
+            l["usernames"]["values"]["LDAP-1"],
+            json!(format!("IRC: {}", m["username"].as_str().unwrap()))
+        );
+        assert!(h["businessTitle"].as_str().unwrap().ends_with(" Mgmt 3"));
+        assert_eq!(h["IsManager"], json!("TRUE"));
+        assert_eq!(h["Hire_Date"], json!("2016-01-01"));
+        assert_ne!(h["WPRDeskNumber"], json!("1234"));
+        // not allowlisted and no string to replace
+        assert!(h.get("Salary").is_none());
+        assert!(l.get("home_directory").is_none());
+        assert!(m.get("age").is_none());
+        assert!(LOCATIONS.contains(&m["location_preference"].as_str().unwrap()));
+        assert_ne!(m["timezone"], json!("Europe/Berlin"));
+        assert_eq!(
+            l["phone_numbers"]["values"]["office"]
+                .as_str()
+                .unwrap()
+                .len(),
+            "+49 30 1234".len()
+        );
+    }
+}
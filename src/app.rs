@@ -1,15 +1,38 @@
+use std::collections::HashMap;
 use std::ffi::OsString;
+use std::fs::create_dir_all;
+use std::io::{stdout, BufWriter, Write};
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
 
 use clap::{App, Arg, ArgMatches, SubCommand};
 use serde_json;
+use serde_json::Value;
 
-use hris::map_hris;
+use anonymize::{anonymize_all, Anonymizer};
+use attributes::{self, user};
+use avatar::{convert_placeholder, AvatarConfig};
+use download::{DownloadConfig, Downloader, Downloads, Pictures};
+use generate::{generate, Options as GenerateOptions};
+use hris::{map_hris, HrisFields};
+use jws::{sign_profile, Key, Keyset, PublicKey};
 use ldap::map_ldap;
-use loader::{load_all, Data};
+use loader::{load_all, load_json, load_profiles, Data};
 use mozillians::map_mozillians;
-use schema::Profile;
-use writer::write_enumerated;
+use publish::{ClientCredentials, PublishConfig, Publisher};
+use publisher::{self, publisher_name, TrustPolicy};
+use schema::PublisherAuthority::{Cis, Hris, Ldap, Mozilliansorg};
+use schema::{Display, Profile, PublisherAuthority};
+use split::{FileNaming, SplitOptions, SplitWriter};
+use timestamp;
+use username::{
+    dedupe, keep_generated, Claim, Extractors, UsernameKey, UsernamePolicy, Usernames,
+};
+use validate::{check_profile, Validator};
+use verify::verify_profile;
+use view::{filter, parse_view, Mode};
+use writer::{write, write_enumerated};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -34,13 +57,29 @@ where
             SubCommand::with_name("merge")
                 .about("merge data into profile v2")
                 .arg(
-                    Arg::with_name("entropy")
-                        .short("e")
-                        .long("entropy")
+                    Arg::with_name("username_key")
+                        .long("username-key")
                         .takes_value(true)
                         .number_of_values(1)
-                        .required(true)
-                        .help("add some entropy to the usernames"),
+                        .help("username key file [default: $V2CONV_USERNAME_KEY]"),
+                ).arg(
+                    Arg::with_name("username_extractors")
+                        .long("username-extractors")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .help("json file with the patterns for usernames in ldap"),
+                ).arg(
+                    Arg::with_name("username_policy")
+                        .long("username-policy")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .help("json file with the charset, length and reserved usernames"),
+                ).arg(
+                    Arg::with_name("previous")
+                        .long("previous")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .help("earlier output whose generated usernames and timestamps are kept"),
                 ).arg(
                     Arg::with_name("hris")
                         .short("w")
@@ -49,6 +88,12 @@ where
                         .number_of_values(1)
                         .required(false)
                         .help("hris/workday data"),
+                ).arg(
+                    Arg::with_name("hris_fields")
+                        .long("hris-fields")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .help("json file with the hris columns to keep in access_information"),
                 ).arg(
                     Arg::with_name("ldap")
                         .short("l")
@@ -76,6 +121,58 @@ where
                         .takes_value(true)
                         .number_of_values(1)
                         .help("output dir for avatars"),
+                ).arg(
+                    Arg::with_name("avatar_cache")
+                        .long("avatar-cache")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .help("cache dir for downloaded avatars"),
+                ).arg(
+                    Arg::with_name("download_workers")
+                        .long("download-workers")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .default_value("8")
+                        .help("number of concurrent avatar downloads"),
+                ).arg(
+                    Arg::with_name("download_timeout")
+                        .long("download-timeout")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .default_value("10")
+                        .help("timeout in seconds for an avatar download"),
+                ).arg(
+                    Arg::with_name("download_retries")
+                        .long("download-retries")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .default_value("3")
+                        .help("retries for failed avatar downloads"),
+                ).arg(
+                    Arg::with_name("max_avatar_bytes")
+                        .long("max-avatar-bytes")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .help("size limit for avatars, overrides max_bytes of the avatar config"),
+                ).arg(
+                    Arg::with_name("avatar_base_url")
+                        .long("avatar-base-url")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .help("base url for full picture urls"),
+                ).arg(
+                    Arg::with_name("avatar_placeholder")
+                        .long("avatar-placeholder")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .possible_values(&["initials", "identicon"])
+                        .help("generate placeholders for profiles without a picture"),
+                ).arg(
+                    Arg::with_name("avatar_config")
+                        .long("avatar-config")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .help("json file with the avatar sizes, formats, filter and layout"),
                 ).arg(
                     Arg::with_name("avatars_in")
                         .short("i")
@@ -83,6 +180,48 @@ where
                         .takes_value(true)
                         .number_of_values(1)
                         .help("input fir for avatars"),
+                ).arg(
+                    Arg::with_name("now")
+                        .long("now")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .help("pin the current time (RFC 3339) for reproducible output"),
+                ).arg(
+                    Arg::with_name("reproducible")
+                        .long("reproducible")
+                        .help("fail unless the time is pinned by --now or SOURCE_DATE_EPOCH"),
+                ).arg(
+                    Arg::with_name("trust")
+                        .long("trust")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .default_value("hris,ldap")
+                        .help("comma separated publishers whose attributes are verified"),
+                ).arg(
+                    Arg::with_name("sign")
+                        .long("sign")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("sign attributes with <publisher>=<pem or jwk file>"),
+                ).arg(
+                    Arg::with_name("view")
+                        .long("view")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .possible_values(&[
+                            "public",
+                            "authenticated",
+                            "vouched",
+                            "ndaed",
+                            "staff",
+                            "private",
+                        ]).help("only keep attributes this audience may see"),
+                ).arg(
+                    Arg::with_name("drop")
+                        .long("drop")
+                        .requires("view")
+                        .help("drop hidden attributes instead of nulling them"),
                 ).arg(
                     Arg::with_name("split")
                         .short("s")
@@ -91,6 +230,230 @@ where
                         .takes_value(true)
                         .number_of_values(1)
                         .help("split output in chunks of s"),
+                ).arg(
+                    Arg::with_name("split_bytes")
+                        .long("split-bytes")
+                        .requires("out")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .help("split output in files of at most this many bytes"),
+                ).arg(
+                    Arg::with_name("split_naming")
+                        .long("split-naming")
+                        .requires("out")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .possible_values(&["index", "range", "hash"])
+                        .help("name split files by index, first and last user id or hash"),
+                ).arg(
+                    Arg::with_name("gzip")
+                        .long("gzip")
+                        .requires("out")
+                        .help("gzip split files"),
+                ).arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .possible_values(&["json", "ndjson"])
+                        .default_value("json")
+                        .help("a json array or one compact profile per line"),
+                ),
+        ).subcommand(
+            SubCommand::with_name("verify")
+                .about("verify the signatures of profile v2 files")
+                .arg(
+                    Arg::with_name("key")
+                        .short("k")
+                        .long("key")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("verification key as <publisher>=<pem or jwk file>"),
+                ).arg(
+                    Arg::with_name("profiles")
+                        .required(true)
+                        .help("profile file or directory of split output"),
+                ),
+        ).subcommand(
+            SubCommand::with_name("validate")
+                .about("validate profile v2 files against the bundled schema")
+                .arg(
+                    Arg::with_name("profiles")
+                        .required(true)
+                        .help("profile file or directory of split output"),
+                ),
+        ).subcommand(
+            SubCommand::with_name("filter")
+                .about("restrict profile v2 files to what an audience may see")
+                .arg(
+                    Arg::with_name("view")
+                        .long("view")
+                        .required(true)
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .possible_values(&[
+                            "public",
+                            "authenticated",
+                            "vouched",
+                            "ndaed",
+                            "staff",
+                            "private",
+                        ]).help("only keep attributes this audience may see"),
+                ).arg(
+                    Arg::with_name("drop")
+                        .long("drop")
+                        .requires("view")
+                        .help("drop hidden attributes instead of nulling them"),
+                ).arg(
+                    Arg::with_name("profiles")
+                        .required(true)
+                        .help("profile file or directory of split output"),
+                ),
+        ).subcommand(
+            SubCommand::with_name("anonymize")
+                .about("replace personal data in the input sources with fake values")
+                .arg(
+                    Arg::with_name("hris")
+                        .short("w")
+                        .long("hris")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .help("hris/workday data"),
+                ).arg(
+                    Arg::with_name("ldap")
+                        .short("l")
+                        .long("ldap")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .help("ldap data"),
+                ).arg(
+                    Arg::with_name("mozillians")
+                        .short("m")
+                        .long("mozillians")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .help("mozillians data"),
+                ).arg(
+                    Arg::with_name("secret_file")
+                        .long("secret-file")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .help("secret file (default: $V2CONV_ANONYMIZE_SECRET or random)"),
+                ),
+        ).subcommand(
+            SubCommand::with_name("generate")
+                .about("generate synthetic input sources")
+                .arg(
+                    Arg::with_name("count")
+                        .short("n")
+                        .long("count")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .required(true)
+                        .help("number of people"),
+                ).arg(
+                    Arg::with_name("staff")
+                        .long("staff")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .default_value("0.5")
+                        .help("share of people only in hris and ldap"),
+                ).arg(
+                    Arg::with_name("staff_mozillians")
+                        .long("staff-mozillians")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .default_value("0.3")
+                        .help("share of people in hris, ldap and mozillians"),
+                ).arg(
+                    Arg::with_name("edge_cases")
+                        .long("edge-cases")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .default_value("0.1")
+                        .help("share of people hitting an edge case"),
+                ).arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .default_value("0")
+                        .help("seed for the random generator"),
+                ),
+        ).subcommand(
+            SubCommand::with_name("publish")
+                .about("post profile v2 files to a cis change endpoint")
+                .arg(
+                    Arg::with_name("profiles")
+                        .required(true)
+                        .help("profile file or directory of split output"),
+                ).arg(
+                    Arg::with_name("url")
+                        .long("url")
+                        .required(true)
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .help("change endpoint the batches are posted to"),
+                ).arg(
+                    Arg::with_name("batch_size")
+                        .long("batch-size")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .default_value("10")
+                        .help("profiles per request"),
+                ).arg(
+                    Arg::with_name("retries")
+                        .long("retries")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .default_value("3")
+                        .help("retries for failed requests"),
+                ).arg(
+                    Arg::with_name("timeout")
+                        .long("timeout")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .default_value("30")
+                        .help("timeout in seconds for a request"),
+                ).arg(
+                    Arg::with_name("rate_limit")
+                        .long("rate-limit")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .help("most requests per second"),
+                ).arg(
+                    Arg::with_name("token_url")
+                        .long("token-url")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .requires("client_id")
+                        .help("oauth token endpoint for the client credentials grant"),
+                ).arg(
+                    Arg::with_name("client_id")
+                        .long("client-id")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .requires("token_url")
+                        .help("oauth client id"),
+                ).arg(
+                    Arg::with_name("client_secret")
+                        .long("client-secret")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .help("oauth client secret file [default: $V2CONV_CLIENT_SECRET]"),
+                ).arg(
+                    Arg::with_name("audience")
+                        .long("audience")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .help("audience of the requested token"),
+                ).arg(
+                    Arg::with_name("log")
+                        .long("log")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .help("file for the result of every profile [default: stdout]"),
                 ),
         ).subcommand(SubCommand::with_name("default").about("output default empty profile v2"))
         .get_matches_from(itr)
@@ -102,8 +465,31 @@ where
     T: Into<OsString> + Clone,
 {
     let all_matches = parse_args(itr);
+    if let Some(m) = all_matches.subcommand_matches("anonymize") {
+        return run_anonymize(m, all_matches.value_of("out"));
+    }
+    if let Some(m) = all_matches.subcommand_matches("generate") {
+        return run_generate(m, all_matches.value_of("out"));
+    }
+    if let Some(m) = all_matches.subcommand_matches("publish") {
+        return run_publish(m);
+    }
+    if let Some(m) = all_matches.subcommand_matches("merge") {
+        if let (Some(out), Some(options)) = (all_matches.value_of("out"), split_args(m)?) {
+            return run_merge_split(m, out, options);
+        }
+        if m.value_of("format") == Some("ndjson") {
+            return run_merge_ndjson(m);
+        }
+    }
     let out = if let Some(m) = all_matches.subcommand_matches("merge") {
         run_merge(m)
+    } else if let Some(m) = all_matches.subcommand_matches("verify") {
+        run_verify(m)
+    } else if let Some(m) = all_matches.subcommand_matches("validate") {
+        run_validate(m)
+    } else if let Some(m) = all_matches.subcommand_matches("filter") {
+        run_filter(m)
     } else if let Some(m) = all_matches.subcommand_matches("default") {
         run_default(m)
     } else {
@@ -130,7 +516,272 @@ pub fn run_default(_: &ArgMatches) -> Result<Vec<String>, String> {
     Ok(out)
 }
 
-pub fn run_merge(matches: &ArgMatches) -> Result<Vec<String>, String> {
+pub fn run_verify(matches: &ArgMatches) -> Result<Vec<String>, String> {
+    let keys = Keyset::load(
+        matches.values_of("key").into_iter().flatten(),
+        PublicKey::load,
+    )?;
+    let profiles = load_profiles(matches.value_of("profiles").unwrap_or_default())?;
+    let mut failed = 0;
+    for p in &profiles {
+        let problems = verify_profile(p, &keys);
+        if !problems.is_empty() {
+            failed += 1;
+            eprintln!("{}:", user(p));
+            for (attribute, problem) in problems {
+                eprintln!("  {}: {}", attribute, problem);
+            }
+        }
+    }
+    if failed > 0 {
+        Err(format!(
+            "{} of {} profiles failed verification",
+            failed,
+            profiles.len()
+        ))
+    } else {
+        Ok(vec![format!("verified {} profiles", profiles.len())])
+    }
+}
+
+pub fn run_validate(matches: &ArgMatches) -> Result<Vec<String>, String> {
+    let validator = Validator::bundled();
+    let profiles = load_profiles(matches.value_of("profiles").unwrap_or_default())?;
+    let mut failed = 0;
+    for p in &profiles {
+        let violations = validator.validate(p);
+        if !violations.is_empty() {
+            failed += 1;
+            for v in violations {
+                eprintln!("{}: {}: {}", user(p), v.pointer, v.message);
+            }
+        }
+    }
+    if failed > 0 {
+        Err(format!(
+            "{} of {} profiles failed validation",
+            failed,
+            profiles.len()
+        ))
+    } else {
+        Ok(vec![format!("validated {} profiles", profiles.len())])
+    }
+}
+
+/// Writes `hris.json`, `ldap.json` and `mozillians.json` to `out`.
+pub fn run_anonymize(matches: &ArgMatches, out: Option<&str>) -> Result<(), String> {
+    let out = PathBuf::from(out.ok_or_else(|| String::from("anonymize needs --out <dir>"))?);
+    let anonymizer = Anonymizer::load(matches.value_of("secret_file"))?;
+    let load = |name| matches.value_of(name).map(load_json).transpose();
+    let (hris, ldap, mozillians) = anonymize_all(
+        &anonymizer,
+        load("hris")?,
+        load("ldap")?,
+        load("mozillians")?,
+    );
+    create_dir_all(&out).map_err(|e| format!("{}", e))?;
+    for (name, data) in &[
+        ("hris.json", hris),
+        ("ldap.json", ldap),
+        ("mozillians.json", mozillians),
+    ] {
+        if let Some(data) = data {
+            let s = serde_json::to_string_pretty(data).map_err(|e| format!("{}", e))?;
+            write(&out.join(name), s.as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes `hris.json`, `ldap.json`, `mozillians.json` and the LDAP photos
+/// in `photos/` to `out`.
+pub fn run_generate(matches: &ArgMatches, out: Option<&str>) -> Result<(), String> {
+    let out = PathBuf::from(out.ok_or_else(|| String::from("generate needs --out <dir>"))?);
+    let options = GenerateOptions {
+        count: arg(matches, "count")?,
+        staff: arg(matches, "staff")?,
+        staff_mozillians: arg(matches, "staff_mozillians")?,
+        edge_cases: arg(matches, "edge_cases")?,
+        seed: arg(matches, "seed")?,
+    };
+    let generated = generate(&options)?;
+    let photos = out.join("photos");
+    create_dir_all(&photos).map_err(|e| format!("{}", e))?;
+    for (name, data) in &[
+        ("hris.json", generated.hris),
+        ("ldap.json", generated.ldap),
+        ("mozillians.json", generated.mozillians),
+    ] {
+        let s = serde_json::to_string_pretty(data).map_err(|e| format!("{}", e))?;
+        write(&out.join(name), s.as_bytes())?;
+    }
+    for (name, img) in &generated.avatars {
+        img.save(photos.join(name))
+            .map_err(|e| format!("error writing {}: {}", name, e))?;
+    }
+    Ok(())
+}
+
+/// Posts the profiles in batches and writes one JSON line per profile with
+/// its outcome. Fails if any profile was not published.
+pub fn run_publish(matches: &ArgMatches) -> Result<(), String> {
+    let profiles = load_profiles(matches.value_of("profiles").unwrap_or_default())?;
+    let credentials = match (matches.value_of("token_url"), matches.value_of("client_id")) {
+        (Some(token_url), Some(client_id)) => Some(ClientCredentials {
+            token_url: String::from(token_url),
+            client_id: String::from(client_id),
+            client_secret: ClientCredentials::load_secret(matches.value_of("client_secret"))?,
+            audience: matches.value_of("audience").map(String::from),
+        }),
+        _ => None,
+    };
+    let interval = match matches.value_of("rate_limit") {
+        Some(s) => {
+            let rate: f64 = arg(matches, "rate_limit")?;
+            // also rules out NaN, infinity and rates too small for a Duration
+            if !rate.is_finite() || rate <= 0.0 || 1.0 / rate >= u64::MAX as f64 {
+                return Err(format!("rate limit must be a positive number: {}", s));
+            }
+            Some(Duration::from_secs_f64(1.0 / rate))
+        }
+        None => None,
+    };
+    let mut publisher = Publisher::new(PublishConfig {
+        url: String::from(matches.value_of("url").unwrap_or_default()),
+        batch_size: arg(matches, "batch_size")?,
+        retries: arg(matches, "retries")?,
+        timeout: Duration::from_secs(arg(matches, "timeout")?),
+        interval,
+        credentials,
+        ..PublishConfig::default()
+    })?;
+    let outcomes = publisher.publish(&profiles);
+    let mut log = String::new();
+    for o in &outcomes {
+        log += &serde_json::to_string(o).map_err(|e| format!("{}", e))?;
+        log += "\n";
+    }
+    match matches.value_of("log") {
+        Some(path) => write(&PathBuf::from(path), log.as_bytes())?,
+        None => print!("{}", log),
+    }
+    let failed = outcomes.iter().filter(|o| !o.published).count();
+    if failed > 0 {
+        return Err(format!(
+            "{} of {} profiles were not published",
+            failed,
+            outcomes.len()
+        ));
+    }
+    eprintln!("published {} profiles", outcomes.len());
+    Ok(())
+}
+
+fn arg<T: FromStr>(matches: &ArgMatches, name: &str) -> Result<T, String> {
+    let v = matches.value_of(name).unwrap_or_default();
+    v.parse()
+        .map_err(|_| format!("invalid value for {}: {}", name, v))
+}
+
+fn view_args(matches: &ArgMatches) -> Result<Option<(Display, Mode)>, String> {
+    let mode = if matches.is_present("drop") {
+        Mode::Drop
+    } else {
+        Mode::Null
+    };
+    Ok(match matches.value_of("view") {
+        Some(v) => Some((parse_view(v)?, mode)),
+        None => None,
+    })
+}
+
+pub fn run_filter(matches: &ArgMatches) -> Result<Vec<String>, String> {
+    let view = view_args(matches)?;
+    let mut profiles = load_profiles(matches.value_of("profiles").unwrap_or_default())?;
+    if let Some((view, mode)) = view {
+        for p in &mut profiles {
+            filter(p, &view, mode);
+        }
+    }
+    let out = vec![serde_json::to_string_pretty(&profiles).map_err(|e| format!("{}", e))?];
+    Ok(out)
+}
+
+/// Applies a single source to a profile and records which attributes it
+/// changed.
+fn stage<F>(
+    p: Profile,
+    publisher: PublisherAuthority,
+    policy: &TrustPolicy,
+    now: &str,
+    f: F,
+) -> Result<Profile, String>
+where
+    F: FnOnce(Profile) -> Result<Profile, String>,
+{
+    let before = attributes::to_value(&p)?;
+    let mut after = attributes::to_value(&f(p)?)?;
+    let paths = attributes::changed(&before, &after);
+    timestamp::touch(&mut after, &paths, now);
+    publisher::attribute(&mut after, &paths, &publisher, policy);
+    attributes::from_value(after)
+}
+
+/// The mozillians picture of `d` unless the profile gets its picture from
+/// LDAP.
+fn mozillians_picture(d: &Data, avatars_in: &Option<PathBuf>) -> Option<String> {
+    let ldap = avatars_in.is_some() && d.hris.is_object() && d.ldap["picture"]["value"].is_string();
+    if ldap {
+        None
+    } else {
+        d.mozillians["picture"].as_str().map(String::from)
+    }
+}
+
+/// Gives a profile without a picture a placeholder. The placeholder is
+/// published by CIS, as no source provided it, and never marked as verified.
+fn with_placeholder(
+    p: Profile,
+    policy: &TrustPolicy,
+    now: &str,
+    avatars_out: &Option<PathBuf>,
+    config: &AvatarConfig,
+) -> Result<Profile, String> {
+    let (out, id) = match (avatars_out, &p.identities.dinopark_id.value) {
+        (Some(out), Some(id)) if p.picture.value.is_none() => (out, id.clone()),
+        _ => return Ok(p),
+    };
+    let full_name = [&p.first_name.value, &p.last_name.value]
+        .iter()
+        .filter_map(|n| n.as_ref().map(String::as_str))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let picture = match convert_placeholder(out, &id, &full_name, config) {
+        Ok(Some(picture)) => picture,
+        Ok(None) => return Ok(p),
+        Err(e) => {
+            eprintln!("error generating placeholder for {}: {}", id, e);
+            return Ok(p);
+        }
+    };
+    let mut p = stage(p, PublisherAuthority::Cis, policy, now, |mut p| {
+        p.picture.value = Some(picture);
+        Ok(p)
+    })?;
+    p.picture.metadata.verified = false;
+    Ok(p)
+}
+
+/// Merges the sources and hands every finished profile to `emit`.
+///
+/// The sources are merged twice. The first pass keeps only what settles the
+/// order and the usernames of the profiles. The second one merges the
+/// profiles again in that order, and every profile is signed, validated and
+/// emitted as soon as it is finished, so only the sources stay in memory.
+fn merge<F>(matches: &ArgMatches, mut emit: F) -> Result<(), String>
+where
+    F: FnMut(Value) -> Result<(), String>,
+{
     let data = load_all(
         matches.value_of("hris").unwrap_or_default(),
         matches.value_of("ldap").unwrap_or_default(),
@@ -138,81 +789,398 @@ pub fn run_merge(matches: &ArgMatches) -> Result<Vec<String>, String> {
     )?;
     let avatars_in = matches.value_of("avatars_in").map(PathBuf::from);
     let avatars_out = matches.value_of("avatars_out").map(PathBuf::from);
-    let entropy = matches.value_of("entropy").unwrap_or_default();
-    let profiles: Vec<Profile> = data
-        .into_iter()
-        .filter(|(_, d)| {
-            if matches.is_present("mozillians_only") {
-                d.mozillians.is_object()
-            } else {
-                true
+    let mut avatars = match matches.value_of("avatar_config") {
+        Some(path) => AvatarConfig::load(path)?,
+        None => AvatarConfig::default(),
+    };
+    avatars.base_url = matches.value_of("avatar_base_url").map(String::from);
+    if matches.is_present("max_avatar_bytes") {
+        avatars.max_bytes = arg(matches, "max_avatar_bytes")?;
+    }
+    if let Some(style) = matches.value_of("avatar_placeholder") {
+        avatars.placeholder = serde_json::from_value(json!(style)).map_err(|e| format!("{}", e))?;
+    }
+    let mut usernames = Usernames {
+        key: UsernameKey::load(matches.value_of("username_key"))?,
+        extractors: match matches.value_of("username_extractors") {
+            Some(path) => Extractors::load(path)?,
+            None => Extractors::default(),
+        },
+        policy: match matches.value_of("username_policy") {
+            Some(path) => UsernamePolicy::load(path)?,
+            None => UsernamePolicy::default(),
+        },
+        report: true,
+    };
+    let previous = match matches.value_of("previous") {
+        Some(path) => load_profiles(path)?,
+        None => vec![],
+    };
+    let now = match matches.value_of("now") {
+        None if matches.is_present("reproducible") => timestamp::source_date_epoch()?
+            .ok_or("--reproducible needs --now or SOURCE_DATE_EPOCH")?,
+        pinned => timestamp::now(pinned)?,
+    };
+    let policy = TrustPolicy::parse(matches.value_of("trust").unwrap_or_default())?;
+    let keys = Keyset::load(matches.values_of("sign").into_iter().flatten(), Key::load)?;
+    if matches.is_present("sign") {
+        // attributes no source sets are published by cis
+        let missing: Vec<String> = [Cis, Hris, Ldap, Mozilliansorg]
+            .iter()
+            .filter(|p| keys.get(p).is_none())
+            .map(publisher_name)
+            .collect();
+        if !missing.is_empty() {
+            return Err(format!("--sign needs a key for {}", missing.join(", ")));
+        }
+    }
+    let validator = Validator::bundled();
+    let view = view_args(matches)?;
+    let hris_fields = match matches.value_of("hris_fields") {
+        Some(path) => HrisFields::load(path)?,
+        None => HrisFields::default(),
+    };
+    let workers: usize = arg(matches, "download_workers")?;
+    let downloader = match &avatars_out {
+        Some(_) => Some(Downloader::new(DownloadConfig {
+            workers,
+            timeout: Duration::from_secs(arg(matches, "download_timeout")?),
+            retries: arg(matches, "download_retries")?,
+            max_bytes: avatars.max_bytes,
+            cache: matches.value_of("avatar_cache").map(PathBuf::from),
+            ..DownloadConfig::default()
+        })?),
+        None => None,
+    };
+    for (column, count) in hris_fields.unexpected(data.values().map(|d| &d.hris)) {
+        eprintln!(
+            "leaving out unexpected hris column {} ({} records)",
+            column, count
+        );
+    }
+    let merge_one = |email: &str,
+                     d: Data,
+                     avatars_out: &Option<PathBuf>,
+                     pictures: &Pictures,
+                     usernames: &Usernames| {
+        let Data {
+            hris,
+            ldap,
+            mozillians,
+        } = d;
+        let created = timestamp::created(&hris, &mozillians);
+        let merged = if hris.is_object() && ldap.is_object() {
+            stage(Profile::default(), Hris, &policy, &now, |p| {
+                Ok(map_hris(p, &hris, &hris_fields))
+            }).and_then(|p| {
+                stage(p, Ldap, &policy, &now, |p| {
+                    map_ldap(p, ldap, &avatars_in, avatars_out, &avatars, usernames)
+                        .map_err(|e| format!("{}", e))
+                })
+            }).and_then(|p| {
+                stage(p, Mozilliansorg, &policy, &now, |p| {
+                    map_mozillians(p, mozillians, avatars_out, &avatars, pictures, usernames)
+                        .map_err(|e| format!("{}", e))
+                })
+            })
+        } else if mozillians.is_object() {
+            stage(Profile::default(), Mozilliansorg, &policy, &now, |p| {
+                map_mozillians(p, mozillians, avatars_out, &avatars, pictures, usernames)
+                    .map_err(|e| format!("{}", e))
+            })
+        } else {
+            if hris.is_object() {
+                eprintln!("no hris for {}", email);
             }
-        }).filter_map(|(email, d)| {
-            let Data {
-                hris,
-                ldap,
-                mozillians,
-            } = d;
-            if hris.is_object() && ldap.is_object() {
-                let mut p = Profile::default();
-                p = map_hris(p, &hris);
-                match map_ldap(p, ldap, &avatars_in, &avatars_out, entropy) {
-                    Ok(l) => {
-                        p = l;
-                    }
-                    Err(e) => {
-                        eprintln!("{}", e);
-                        return None;
-                    }
-                }
-                match map_mozillians(p, mozillians, &avatars_out) {
-                    Ok(m) => {
-                        p = m;
-                    }
-                    Err(e) => {
-                        eprintln!("{}", e);
-                        return None;
+            if ldap.is_object() {
+                eprintln!("no ldap for {}", email);
+            }
+            return None;
+        };
+        let merged = merged.and_then(|p| {
+            with_placeholder(p, &policy, &now, avatars_out, &avatars)
+        });
+        match merged {
+            Ok(p) => Some((created, p)),
+            Err(e) => {
+                eprintln!("{}", e);
+                None
+            }
+        }
+    };
+    // first pass, without pictures
+    let no_pictures = Pictures {
+        downloader: None,
+        downloads: Downloads::new(),
+    };
+    let mut staged: Vec<(&str, Claim)> = vec![];
+    for (email, d) in &data {
+        if matches.is_present("mozillians_only") && !d.mozillians.is_object() {
+            continue;
+        }
+        if let Some((_, p)) = merge_one(email, d.clone(), &None, &no_pictures, &usernames) {
+            staged.push((email, Claim::new(&p)));
+        }
+    }
+    // the sources come in hash map order, profiles without user id and email
+    // are told apart by the key of their sources
+    staged.sort_by(|(a_key, a), (b_key, b)| {
+        (&a.user_id, &a.primary_email, a_key).cmp(&(&b.user_id, &b.primary_email, b_key))
+    });
+    let (mut emails, mut claims): (Vec<String>, Vec<Claim>) = staged
+        .into_iter()
+        .map(|(email, c)| (String::from(email), c))
+        .unzip();
+    let kept = keep_generated(&mut claims, &previous);
+    if kept > 0 {
+        eprintln!("kept {} generated usernames from earlier output", kept);
+    }
+    let (renames, clashes) = dedupe(&mut claims, &usernames.key);
+    for r in renames {
+        eprintln!(
+            "username {} of {} is taken, using {}",
+            r.from, r.user_id, r.to
+        );
+    }
+    let unknown = |id: &str| String::from(if id.is_empty() { "<unknown user>" } else { id });
+    for c in &clashes {
+        eprintln!(
+            "leaving out {}: username {} is taken by {}",
+            unknown(&c.user_id),
+            c.username,
+            unknown(&c.other)
+        );
+    }
+    for c in clashes.iter().rev() {
+        emails.remove(c.index);
+        claims.remove(c.index);
+    }
+
+    // second pass, the mapping was reported on in the first one
+    usernames.report = false;
+    let earlier: HashMap<&str, &Value> = previous
+        .iter()
+        .filter_map(|p| p["user_id"]["value"].as_str().map(|id| (id, p)))
+        .collect();
+    let total = emails.len();
+    let mut failed = 0;
+    let mut pictures_out = vec![];
+    let mut data = data;
+    let mut entries = emails.into_iter().zip(claims);
+    // pictures are downloaded for a batch of profiles at a time, so only
+    // the pictures of one batch are held in memory
+    loop {
+        let batch: Vec<(String, Data, Claim)> = entries
+            .by_ref()
+            .take(workers.max(1) * 4)
+            .filter_map(|(email, c)| data.remove(&email).map(|d| (email, d, c)))
+            .collect();
+        if batch.is_empty() {
+            break;
+        }
+        let urls = batch
+            .iter()
+            .filter_map(|(_, d, _)| mozillians_picture(d, &avatars_in));
+        let pictures = Pictures {
+            downloader: downloader.as_ref(),
+            downloads: match &downloader {
+                Some(downloader) => downloader.fetch_all(urls),
+                None => Downloads::new(),
+            },
+        };
+        for (email, d, claim) in batch {
+            let (created, mut p) =
+                match merge_one(&email, d, &avatars_out, &pictures, &usernames) {
+                    Some(merged) => merged,
+                    None => {
+                        failed += 1;
+                        continue;
                     }
+                };
+            if let Some(username) = claim.username {
+                p.usernames
+                    .values
+                    .insert(String::from("mozilliansorg"), username.into());
+            }
+            if avatars_out.is_some() {
+                pictures_out.extend(p.picture.value.clone());
+            }
+            let previous = p.user_id.value.as_deref().and_then(|id| earlier.get(id));
+            let created = created
+                .or_else(|| {
+                    previous.and_then(|b| b["created"]["value"].as_str().map(String::from))
+                })
+                .unwrap_or_else(|| now.clone());
+            let finished = timestamp::finish(p, &created, &now).and_then(|p| match previous {
+                Some(previous) => timestamp::carry(p, previous),
+                None => Ok(p),
+            });
+            let finished = match finished {
+                Ok(p) if matches.is_present("sign") => {
+                    let id = unknown(p.user_id.value.as_deref().unwrap_or_default());
+                    Ok(sign_profile(p, &keys).map_err(|e| format!("{}: {}", id, e))?)
                 }
-                return Some(p);
-            } else if mozillians.is_object() {
-                let mut p = Profile::default();
-                match map_mozillians(p, mozillians, &avatars_out) {
-                    Ok(m) => {
-                        p = m;
-                    }
-                    Err(e) => {
-                        eprintln!("{}", e);
-                        return None;
+                finished => finished,
+            };
+            let finished = finished
+                .and_then(|p| check_profile(&validator, p))
+                .and_then(|p| attributes::to_value(&p))
+                .map(|mut v| {
+                    if let Some((view, mode)) = &view {
+                        filter(&mut v, view, *mode);
                     }
-                }
-                return Some(p);
-            } else {
-                if hris.is_object() {
-                    eprintln!("no hris for {}", email);
-                }
-                if ldap.is_object() {
-                    eprintln!("no ldap for {}", email);
+                    v
+                });
+            match finished {
+                Ok(p) => emit(p)?,
+                Err(e) => {
+                    failed += 1;
+                    eprintln!("{}", e);
                 }
             }
-            None
-        }).collect();
-    if matches.is_present("split") {
-        let split = value_t!(matches.value_of("split"), usize).unwrap_or_else(|e| e.exit());
-        profiles
-            .chunks(split)
-            .map(|p| {
-                serde_json::to_string_pretty(p)
-                    .map(|s| s.to_owned())
-                    .map_err(|e| format!("{}", e))
-            }).collect()
-    } else {
-        let out = vec![
-            serde_json::to_string_pretty(&json!(profiles))
-                .map_err(|e| format!("{}", e))?
-                .to_owned(),
+        }
+    }
+    if let Some(out) = &avatars_out {
+        let manifest = avatars.manifest(out, pictures_out.iter().map(String::as_str))?;
+        let s = serde_json::to_string_pretty(&manifest).map_err(|e| format!("{}", e))?;
+        create_dir_all(out).map_err(|e| format!("{}", e))?;
+        write(&out.join("manifest.json"), s.as_bytes())?;
+    }
+    if failed > 0 {
+        return Err(format!("left out {} of {} profiles", failed, total));
+    }
+    Ok(())
+}
+
+pub fn run_merge(matches: &ArgMatches) -> Result<Vec<String>, String> {
+    let mut profiles = vec![];
+    merge(matches, |p| {
+        profiles.push(p);
+        Ok(())
+    })?;
+    let out = vec![
+        serde_json::to_string_pretty(&json!(profiles))
+            .map_err(|e| format!("{}", e))?
+            .to_owned(),
+    ];
+
+    Ok(out)
+}
+
+/// Writes every profile to stdout as soon as it is finished, one per line,
+/// without building the whole output. See `merge` for what stays in memory.
+pub fn run_merge_ndjson(matches: &ArgMatches) -> Result<(), String> {
+    let stdout = stdout();
+    merge_ndjson(matches, BufWriter::new(stdout.lock()))
+}
+
+fn merge_ndjson<W: Write>(matches: &ArgMatches, mut out: W) -> Result<(), String> {
+    merge(matches, |p| {
+        serde_json::to_writer(&mut out, &p).map_err(|e| format!("{}", e))?;
+        out.write_all(b"\n").map_err(|e| format!("{}", e))
+    })?;
+    out.flush().map_err(|e| format!("{}", e))
+}
+
+/// Writes the profiles as they are finished into the files of `options`
+/// below `out`.
+pub fn run_merge_split(
+    matches: &ArgMatches,
+    out: &str,
+    options: SplitOptions,
+) -> Result<(), String> {
+    let mut writer = SplitWriter::new(out, options)?;
+    merge(matches, |p| writer.write(&p))?;
+    writer.finish().map(|_| ())
+}
+
+/// The split output options, `None` if the output is a single JSON array.
+fn split_args(matches: &ArgMatches) -> Result<Option<SplitOptions>, String> {
+    let ndjson = matches.value_of("format") == Some("ndjson");
+    let split = ["split", "split_bytes", "split_naming", "gzip"]
+        .iter()
+        .any(|a| matches.is_present(a));
+    if !ndjson && !split {
+        return Ok(None);
+    }
+    let optional = |name| match matches.value_of(name) {
+        Some(_) => arg(matches, name).map(Some),
+        None => Ok(None),
+    };
+    Ok(Some(SplitOptions {
+        profiles: optional("split")?,
+        bytes: optional("split_bytes")?,
+        naming: match matches.value_of("split_naming") {
+            Some(naming) => serde_json::from_value(json!(naming)).map_err(|e| format!("{}", e))?,
+            None => FileNaming::Index,
+        },
+        gzip: matches.is_present("gzip"),
+        ndjson,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reproducible_merge() {
+        let dir = std::env::temp_dir().join(format!("v2conv-merge-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let g = generate(&GenerateOptions {
+            count: 30,
+            edge_cases: 0.3,
+            ..GenerateOptions::default()
+        })
+        .unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        for (name, v) in &[
+            ("hris.json", &g.hris),
+            ("ldap.json", &g.ldap),
+            ("mozillians.json", &g.mozillians),
+        ] {
+            write(&dir.join(name), v.to_string().as_bytes()).unwrap();
+        }
+        write(&dir.join("key"), b"secret").unwrap();
+        let args = vec![
+            String::from("v2conv"),
+            String::from("merge"),
+            format!("--hris={}", path("hris.json")),
+            format!("--ldap={}", path("ldap.json")),
+            format!("--mozillians={}", path("mozillians.json")),
+            format!("--username-key={}", path("key")),
+            String::from("--now=2019-01-01T00:00:00Z"),
+            String::from("--reproducible"),
         ];
+        let merge = || {
+            let matches = parse_args(args.clone());
+            run_merge(matches.subcommand_matches("merge").unwrap()).unwrap()
+        };
+        let first = merge();
+        // every run loads the sources into differently seeded hash maps
+        assert_eq!(first, merge());
+        let profiles: Vec<Value> = serde_json::from_str(&first[0]).unwrap();
+        assert_eq!(profiles.len(), 30);
+        assert!(profiles.windows(2).all(|w| user(&w[0]) <= user(&w[1])));
+
+        // the same profiles, one per line
+        let mut ndjson = vec![];
+        let matches = parse_args(args.clone());
+        merge_ndjson(matches.subcommand_matches("merge").unwrap(), &mut ndjson).unwrap();
+        let lines: Vec<Value> = String::from_utf8(ndjson)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines, profiles);
 
-        Ok(out)
+        // signing needs a key for every publisher a profile can carry
+        write(&dir.join("hris.jwk"), br#"{"kty":"oct","k":"c2VjcmV0"}"#).unwrap();
+        let mut sign = args.clone();
+        sign.push(format!("--sign=hris={}", path("hris.jwk")));
+        let matches = parse_args(sign);
+        let e = run_merge(matches.subcommand_matches("merge").unwrap()).unwrap_err();
+        assert!(e.ends_with("cis, ldap, mozilliansorg"), "{}", e);
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
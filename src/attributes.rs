@@ -0,0 +1,97 @@
+use serde_json::Value;
+
+use schema::Profile;
+
+pub fn is_attribute(v: &Value) -> bool {
+    v.get("metadata").map(Value::is_object).unwrap_or_default()
+        && v.get("signature").map(Value::is_object).unwrap_or_default()
+}
+
+/// The payload of an attribute: `value` for standard attributes,
+/// `values` for lists and access information.
+pub fn payload(v: &Value) -> &Value {
+    match v.get("value") {
+        Some(value) => value,
+        None => v.get("values").unwrap_or(&Value::Null),
+    }
+}
+
+/// Calls `f` with the JSON pointer and the object of every attribute
+/// contained in `v`.
+pub fn walk(v: &Value, f: &mut dyn FnMut(&str, &Value)) {
+    walk_inner(v, "", f)
+}
+
+fn walk_inner(v: &Value, path: &str, f: &mut dyn FnMut(&str, &Value)) {
+    if is_attribute(v) {
+        f(path, v);
+    } else if let Some(o) = v.as_object() {
+        for (k, v) in o {
+            walk_inner(v, &format!("{}/{}", path, k), f);
+        }
+    }
+}
+
+/// Like `walk` but hands out mutable attributes.
+pub fn walk_mut(v: &mut Value, f: &mut dyn FnMut(&str, &mut Value)) {
+    walk_mut_inner(v, "", f)
+}
+
+fn walk_mut_inner(v: &mut Value, path: &str, f: &mut dyn FnMut(&str, &mut Value)) {
+    if is_attribute(v) {
+        f(path, v);
+    } else if let Some(o) = v.as_object_mut() {
+        for (k, v) in o.iter_mut() {
+            walk_mut_inner(v, &format!("{}/{}", path, k), f);
+        }
+    }
+}
+
+/// JSON pointers of all attributes whose payload differs between `before`
+/// and `after`.
+pub fn changed(before: &Value, after: &Value) -> Vec<String> {
+    let mut paths = vec![];
+    walk(after, &mut |path, a| {
+        let unchanged = before
+            .pointer(path)
+            .map(|b| payload(b) == payload(a))
+            .unwrap_or_default();
+        if !unchanged {
+            paths.push(String::from(path));
+        }
+    });
+    paths
+}
+
+/// Identifies the user of a serialized profile in reports.
+pub fn user(p: &Value) -> &str {
+    p["user_id"]["value"]
+        .as_str()
+        .or_else(|| p["primary_email"]["value"].as_str())
+        .unwrap_or("<unknown user>")
+}
+
+pub fn to_value(p: &Profile) -> Result<Value, String> {
+    serde_json::to_value(p).map_err(|e| format!("{}", e))
+}
+
+pub fn from_value(v: Value) -> Result<Profile, String> {
+    serde_json::from_value(v).map_err(|e| format!("{}", e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_changed() {
+        let before = to_value(&Profile::default()).unwrap();
+        let mut p = Profile::default();
+        p.first_name.value = Some(String::from("Hans"));
+        p.staff_information.team.value = Some(String::from("IAM"));
+        let after = to_value(&p).unwrap();
+        let mut changed = changed(&before, &after);
+        changed.sort();
+        assert_eq!(changed, vec!["/first_name", "/staff_information/team"]);
+    }
+}
@@ -1,50 +1,777 @@
+use std::cmp::{max, min};
+use std::collections::BTreeSet;
 use std::fs;
-use std::fs::File;
-use std::io::BufRead;
-use std::io::BufReader;
-use std::path::PathBuf;
+use std::io::Cursor;
+use std::path::Path;
 
 use image::DynamicImage;
 use image::FilterType;
 use image::GenericImageView;
-use image::ImageResult;
+use image::ImageDecoder;
+use image::ImageFormat;
+use image::ImageOutputFormat;
+use image::{Rgba, RgbaImage};
+use serde_json::Value;
 
-pub fn convert_buf(buf: &[u8], out: &PathBuf, name: &str) -> Result<(), String> {
-    let img = image::load_from_memory(buf).map_err(|e| format!("{}", e))?;
-    convert(&img, out, name)
+use loader::load_json;
+use placeholder::{render, Placeholder};
+use writer::{sha256_hex, write};
+
+/// Start of the names of generated placeholders.
+pub const PLACEHOLDER_PREFIX: &str = "placeholder-";
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Png,
+    Jpeg,
+    Webp,
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Png => "png",
+            Format::Jpeg => "jpg",
+            Format::Webp => "webp",
+        }
+    }
+}
+
+/// An output format with its quality from 1 to 100 (JPEG and WebP only).
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Encoding {
+    pub format: Format,
+    #[serde(default)]
+    pub quality: Option<u8>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Filter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
+
+impl Filter {
+    fn filter_type(self) -> FilterType {
+        match self {
+            Filter::Nearest => FilterType::Nearest,
+            Filter::Triangle => FilterType::Triangle,
+            Filter::CatmullRom => FilterType::CatmullRom,
+            Filter::Gaussian => FilterType::Gaussian,
+            Filter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// What happens to avatars which are not square.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Square {
+    /// No picture for the profile.
+    Reject,
+    /// Keep the center.
+    Crop,
+    /// Fill up to a square with `background`.
+    Pad,
+    /// Keep the part with the most detail.
+    Saliency,
+}
+
+/// How the renditions of an avatar are named.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Naming {
+    /// After the `dinopark_id` of the profile.
+    Id,
+    /// After the SHA-256 of the source image, so users with the same image
+    /// share the renditions.
+    Hash,
+}
+
+/// Which renditions are written for every avatar.
+///
+/// Loaded from a JSON file like
+/// `{ "sizes": [512, 64], "formats": [{ "format": "webp", "quality": 80 }],
+/// "filter": "lanczos3", "layout": "{size}/{name}.{ext}", "square": "pad",
+/// "background": "#ffffff", "naming": "hash", "max_bytes": 10485760,
+/// "max_dimension": 8192, "max_pixels": 25000000, "placeholder": "initials" }`.
+/// The layout may use `{size}`, `{name}` and `{ext}`. The background is
+/// `#rrggbb` or `#rrggbbaa`. Sources larger than the `max_*` limits are
+/// rejected before they are decoded. Profiles without a picture only get a
+/// placeholder if `placeholder` is set. Missing fields keep their defaults.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct AvatarConfig {
+    pub sizes: Vec<u32>,
+    pub formats: Vec<Encoding>,
+    pub filter: Filter,
+    pub layout: String,
+    pub square: Square,
+    pub background: String,
+    pub naming: Naming,
+    pub max_bytes: u64,
+    /// Longest accepted side of a source image.
+    pub max_dimension: u32,
+    pub max_pixels: u64,
+    pub placeholder: Option<Placeholder>,
+    /// Turns `picture` into a URL of the first rendition. Not part of the
+    /// file since it does not change the renditions.
+    #[serde(skip)]
+    pub base_url: Option<String>,
+}
+
+impl Default for AvatarConfig {
+    fn default() -> Self {
+        AvatarConfig {
+            sizes: vec![230, 100, 40],
+            formats: vec![Encoding {
+                format: Format::Png,
+                quality: None,
+            }],
+            filter: Filter::CatmullRom,
+            layout: String::from("{size}/{name}.{ext}"),
+            square: Square::Crop,
+            background: String::from("#ffffff"),
+            naming: Naming::Id,
+            max_bytes: 10 * 1024 * 1024,
+            max_dimension: 8192,
+            max_pixels: 25_000_000,
+            placeholder: None,
+            base_url: None,
+        }
+    }
+}
+
+/// A file written for an avatar, relative to the avatar output directory.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Rendition {
+    pub size: u32,
+    pub format: Format,
+    pub path: String,
+}
+
+impl AvatarConfig {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let config: AvatarConfig =
+            serde_json::from_value(load_json(path)?).map_err(|e| format!("{}: {}", path, e))?;
+        config.check().map_err(|e| format!("{}: {}", path, e))?;
+        Ok(config)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.sizes.is_empty() || self.formats.is_empty() {
+            return Err(String::from("at least one size and format is needed"));
+        }
+        if let Some(q) = self
+            .formats
+            .iter()
+            .filter_map(|f| f.quality)
+            .find(|q| *q == 0 || *q > 100)
+        {
+            return Err(format!("quality {} is not between 1 and 100", q));
+        }
+        parse_color(&self.background)?;
+        let paths: BTreeSet<String> = self.renditions("x").into_iter().map(|r| r.path).collect();
+        if paths.len() != self.sizes.len() * self.formats.len() {
+            return Err(format!(
+                "layout {} does not tell the renditions apart",
+                self.layout
+            ));
+        }
+        Ok(())
+    }
+
+    fn path(&self, size: u32, format: Format, name: &str) -> String {
+        self.layout
+            .replace("{size}", &size.to_string())
+            .replace("{name}", name)
+            .replace("{ext}", format.extension())
+    }
+
+    /// The files written for the avatar `name`.
+    pub fn renditions(&self, name: &str) -> Vec<Rendition> {
+        let mut renditions = vec![];
+        for size in &self.sizes {
+            for encoding in &self.formats {
+                renditions.push(Rendition {
+                    size: *size,
+                    format: encoding.format,
+                    path: self.path(*size, encoding.format, name),
+                });
+            }
+        }
+        renditions
+    }
+
+    /// The value of `picture` for the avatar `name`: the URL of its first
+    /// rendition if there is a base URL, its file name otherwise.
+    pub fn picture(&self, name: &str) -> String {
+        match &self.base_url {
+            Some(base) => self.url(
+                base,
+                &self.path(self.sizes[0], self.formats[0].format, name),
+            ),
+            None => format!("{}.{}", name, self.formats[0].format.extension()),
+        }
+    }
+
+    /// The avatar name `picture` was made from.
+    pub fn name_of(&self, picture: &str) -> Option<String> {
+        let template = self.picture("\u{0}");
+        let mut parts = template.splitn(2, '\u{0}');
+        let (prefix, suffix) = (parts.next()?, parts.next()?);
+        picture
+            .strip_prefix(prefix)
+            .and_then(|p| p.strip_suffix(suffix))
+            .map(String::from)
+    }
+
+    fn url(&self, base: &str, path: &str) -> String {
+        format!("{}/{}", base.trim_end_matches('/'), path)
+    }
+
+    /// Lists every rendition of the avatars behind `pictures` with its byte
+    /// length and SHA-256 as found below `out`, for `manifest.json`.
+    pub fn manifest<'a>(
+        &self,
+        out: &Path,
+        pictures: impl IntoIterator<Item = &'a str>,
+    ) -> Result<Value, String> {
+        let names: BTreeSet<String> = pictures
+            .into_iter()
+            .filter_map(|p| self.name_of(p))
+            .collect();
+        let mut renditions = vec![];
+        for name in &names {
+            for r in self.renditions(name) {
+                let buf = fs::read(out.join(&r.path)).map_err(|e| format!("{}: {}", r.path, e))?;
+                renditions.push(json!({
+                    "name": name,
+                    "size": r.size,
+                    "format": r.format,
+                    "path": r.path,
+                    "url": self.base_url.as_ref().map(|base| self.url(base, &r.path)),
+                    "bytes": buf.len(),
+                    "sha256": sha256_hex(&buf),
+                    "generated": name.starts_with(PLACEHOLDER_PREFIX),
+                }));
+            }
+        }
+        Ok(json!({
+            "sizes": self.sizes,
+            "formats": self.formats,
+            "filter": self.filter,
+            "renditions": renditions,
+        }))
+    }
+}
+
+/// Writes the renditions of the image in `buf` unless they were written
+/// from the same image with the same configuration before. Returns the
+/// value for `picture`.
+pub fn convert_buf(
+    buf: &[u8],
+    out: &Path,
+    id: &str,
+    config: &AvatarConfig,
+) -> Result<String, String> {
+    let source = sha256_hex(buf);
+    let name = match config.naming {
+        Naming::Id => String::from(id),
+        Naming::Hash => source.clone(),
+    };
+    convert_cached(&source, out, &name, config, || decode(buf, config))
+}
+
+/// Writes the renditions of a placeholder for the profile `id` if
+/// `config.placeholder` asks for one. Returns the value for `picture`,
+/// which is named `placeholder-<id>` to tell it from uploaded avatars.
+pub fn convert_placeholder(
+    out: &Path,
+    id: &str,
+    full_name: &str,
+    config: &AvatarConfig,
+) -> Result<Option<String>, String> {
+    let style = match config.placeholder {
+        Some(style) => style,
+        None => return Ok(None),
+    };
+    let side = config.sizes.iter().cloned().max().unwrap_or_default();
+    let source = sha256_hex(format!("{:?}#{}#{}", style, id, full_name).as_bytes());
+    let name = format!("{}{}", PLACEHOLDER_PREFIX, id);
+    convert_cached(&source, out, &name, config, || {
+        Ok(DynamicImage::ImageRgb8(render(style, id, full_name, side)))
+    })
+    .map(Some)
+}
+
+/// Converts the image `source` is the SHA-256 of unless it was converted
+/// with the same configuration before.
+///
+/// The SHA-256 of the source and the configuration is kept in
+/// `.hashes/<name>` below `out`.
+fn convert_cached<F>(
+    source: &str,
+    out: &Path,
+    name: &str,
+    config: &AvatarConfig,
+    img: F,
+) -> Result<String, String>
+where
+    F: FnOnce() -> Result<DynamicImage, String>,
+{
+    let settings = serde_json::to_string(config).map_err(|e| format!("{}", e))?;
+    let key = sha256_hex(format!("{}#{}", source, settings).as_bytes());
+    let marker = out.join(".hashes").join(name);
+    let unchanged = fs::read_to_string(&marker).ok().as_ref() == Some(&key)
+        && config
+            .renditions(name)
+            .iter()
+            .all(|r| out.join(&r.path).is_file());
+    if unchanged {
+        return Ok(config.picture(name));
+    }
+    let picture = convert(&img()?, out, name, config)?;
+    fs::create_dir_all(out.join(".hashes")).map_err(|e| format!("{}", e))?;
+    write(&marker, key.as_bytes())?;
+    Ok(picture)
 }
 
-pub fn convert_path(path: &PathBuf, out: &PathBuf, name: &str) -> Result<(), String> {
-    let img = open_magic(path).map_err(|e| format!("({}) {}", path.to_string_lossy(), e))?;
-    convert(&img, out, name)
+pub fn convert_path(
+    path: &Path,
+    out: &Path,
+    id: &str,
+    config: &AvatarConfig,
+) -> Result<String, String> {
+    let buf = fs::read(path).map_err(|e| format!("({}) {}", path.to_string_lossy(), e))?;
+    convert_buf(&buf, out, id, config).map_err(|e| format!("({}) {}", path.to_string_lossy(), e))
 }
 
-pub fn convert(img: &DynamicImage, out: &PathBuf, name: &str) -> Result<(), String> {
+/// Decodes `buf` if it is a PNG, JPEG, GIF or WebP within the limits of
+/// `config`, turned upright according to its EXIF orientation. The limits
+/// are checked against the header before any pixels are decoded.
+pub fn decode(buf: &[u8], config: &AvatarConfig) -> Result<DynamicImage, String> {
+    if buf.len() as u64 > config.max_bytes {
+        return Err(format!(
+            "rejected: {} bytes exceed the limit of {}",
+            buf.len(),
+            config.max_bytes
+        ));
+    }
+    let format = image::guess_format(buf).map_err(|_| "rejected: unknown image format")?;
+    let (w, h) = dimensions(buf, format)?;
+    if max(w, h) > config.max_dimension {
+        return Err(format!(
+            "rejected: {}x{} pixels exceed the limit of {} per side",
+            w, h, config.max_dimension
+        ));
+    }
+    if u64::from(w) * u64::from(h) > config.max_pixels {
+        return Err(format!(
+            "rejected: {}x{} pixels exceed the limit of {}",
+            w, h, config.max_pixels
+        ));
+    }
+    let img =
+        image::load_from_memory_with_format(buf, format).map_err(|e| format!("rejected: {}", e))?;
+    Ok(match exif_orientation(buf) {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    })
+}
+
+/// Dimensions from the header of an image in one of the accepted formats.
+fn dimensions(buf: &[u8], format: ImageFormat) -> Result<(u32, u32), String> {
+    let header = |e| format!("rejected: invalid header: {}", e);
+    match format {
+        ImageFormat::PNG => image::png::PNGDecoder::new(Cursor::new(buf))
+            .dimensions()
+            .map_err(header),
+        ImageFormat::JPEG => image::jpeg::JPEGDecoder::new(Cursor::new(buf))
+            .dimensions()
+            .map_err(header),
+        ImageFormat::GIF => image::gif::Decoder::new(Cursor::new(buf))
+            .dimensions()
+            .map_err(header),
+        // the WebP decoder decodes the whole frame to tell its dimensions,
+        // so they are read from the VP8 frame header
+        ImageFormat::WEBP => match (buf.get(12..16), buf.get(23..30)) {
+            (Some(b"VP8 "), Some(frame)) if frame[..3] == [0x9d, 0x01, 0x2a] => Ok((
+                u32::from(u16::from(frame[3]) | u16::from(frame[4]) << 8) & 0x3fff,
+                u32::from(u16::from(frame[5]) | u16::from(frame[6]) << 8) & 0x3fff,
+            )),
+            _ => Err(String::from("rejected: unsupported WebP")),
+        },
+        other => Err(format!("rejected: unsupported image format {:?}", other)),
+    }
+}
+
+/// The EXIF orientation (1 to 8) of a JPEG, 1 if there is none.
+fn exif_orientation(buf: &[u8]) -> u16 {
+    if buf.get(..2) != Some(&[0xff, 0xd8]) {
+        return 1;
+    }
+    let mut pos = 2;
+    while let Some(&[0xff, marker, hi, lo]) = buf.get(pos..pos + 4) {
+        let len = usize::from(hi) << 8 | usize::from(lo);
+        // start of scan, no more metadata
+        if marker == 0xda || len < 2 {
+            break;
+        }
+        let segment = buf.get(pos + 4..pos + 2 + len).unwrap_or_default();
+        if marker == 0xe1 && segment.starts_with(b"Exif\0\0") {
+            return tiff_orientation(&segment[6..]).unwrap_or(1);
+        }
+        pos += 2 + len;
+    }
+    1
+}
+
+fn tiff_orientation(tiff: &[u8]) -> Option<u16> {
+    let big_endian = match tiff.get(..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let u16_at = |pos: usize| {
+        let b = tiff.get(pos..pos + 2)?;
+        Some(if big_endian {
+            u16::from(b[0]) << 8 | u16::from(b[1])
+        } else {
+            u16::from(b[1]) << 8 | u16::from(b[0])
+        })
+    };
+    let u32_at = |pos: usize| {
+        let (a, b) = (u32::from(u16_at(pos)?), u32::from(u16_at(pos + 2)?));
+        Some(if big_endian { a << 16 | b } else { b << 16 | a })
+    };
+    let ifd = u32_at(4)? as usize;
+    (0..usize::from(u16_at(ifd)?))
+        .map(|i| ifd + 2 + 12 * i)
+        .find(|entry| u16_at(*entry) == Some(0x0112))
+        .and_then(|entry| u16_at(entry + 8))
+        .filter(|o| (1..=8).contains(o))
+}
+
+/// Writes the renditions of `img` and returns the value for `picture`.
+///
+/// The renditions are encoded from the pixels alone, so no EXIF, ICC or
+/// text metadata of the source ends up in them.
+pub fn convert(
+    img: &DynamicImage,
+    out: &Path,
+    name: &str,
+    config: &AvatarConfig,
+) -> Result<String, String> {
+    let squared = square(img, config)?;
+    let img = squared.as_ref().unwrap_or(img);
+    for size in &config.sizes {
+        let down_sized = img.resize_to_fill(*size, *size, config.filter.filter_type());
+        for encoding in &config.formats {
+            let rel = config.path(*size, encoding.format, name);
+            let buf = encode(&down_sized, encoding)
+                .map_err(|e| format!("error encoding {} for {}: {}", rel, name, e))?;
+            let path = out.join(&rel);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).map_err(|e| format!("{}", e))?;
+            }
+            write(&path, &buf)
+                .map_err(|e| format!("error writing file ({}) for {}: {}", rel, name, e))?;
+        }
+    }
+    Ok(config.picture(name))
+}
+
+fn parse_color(s: &str) -> Result<Rgba<u8>, String> {
+    let invalid = || format!("invalid color {}", s);
+    let hex = s.trim_start_matches('#');
+    if !s.starts_with('#') || (hex.len() != 6 && hex.len() != 8) {
+        return Err(invalid());
+    }
+    let mut rgba = [0, 0, 0, 255];
+    for (i, c) in rgba.iter_mut().enumerate().take(hex.len() / 2) {
+        let byte = hex.get(2 * i..2 * i + 2).ok_or_else(invalid)?;
+        *c = u8::from_str_radix(byte, 16).map_err(|_| invalid())?;
+    }
+    Ok(Rgba(rgba))
+}
+
+/// `img` made square according to `config.square`, `None` if it is square
+/// enough already.
+fn square(img: &DynamicImage, config: &AvatarConfig) -> Result<Option<DynamicImage>, String> {
     let (w, h) = img.dimensions();
     let ratio = f64::from(w) / f64::from(h);
-    if ratio < 0.95 || ratio > 1.05 {
-        return Err(format!("wrong ascpect ratio: {}", ratio));
-    }
-    for i in &[230, 100, 40] {
-        let down_sized = img.resize_to_fill(*i, *i, FilterType::CatmullRom);
-        let mut path = out.clone();
-        path.push(format!("{}", i));
-        fs::create_dir_all(&path).map_err(|e| format!("{}", e))?;
-        path.push(String::from(name));
-        down_sized
-            .save(path)
-            .map_err(|e| format!("error writing file ({}) for {}: {}", i, name, e))?;
-    }
-    Ok(())
-}
-
-fn open_magic(path: &PathBuf) -> ImageResult<DynamicImage> {
-    let fin = match File::open(path) {
-        Ok(f) => f,
-        Err(err) => return Err(image::ImageError::IoError(err)),
+    if (0.95..=1.05).contains(&ratio) {
+        return Ok(None);
+    }
+    let side = min(w, h);
+    let squared = match config.square {
+        Square::Reject => return Err(format!("wrong ascpect ratio: {}", ratio)),
+        Square::Crop => {
+            let (x, y) = ((w - side) / 2, (h - side) / 2);
+            img.clone().crop(x, y, side, side)
+        }
+        Square::Saliency => {
+            let offset = salient_offset(img, side);
+            let (x, y) = if w > h { (offset, 0) } else { (0, offset) };
+            img.clone().crop(x, y, side, side)
+        }
+        Square::Pad => {
+            let side = max(w, h);
+            let mut padded = RgbaImage::from_pixel(side, side, parse_color(&config.background)?);
+            image::imageops::overlay(&mut padded, &img.to_rgba(), (side - w) / 2, (side - h) / 2);
+            DynamicImage::ImageRgba8(padded)
+        }
     };
-    let mut fin = BufReader::new(fin);
+    Ok(Some(squared))
+}
 
-    let format = image::guess_format(fin.fill_buf().map_err(image::ImageError::from)?)?;
-    image::load(fin, format)
+/// Offset along the longer side of the `side` wide window with the most
+/// luma gradient, a cheap stand-in for where the subject is.
+fn salient_offset(img: &DynamicImage, side: u32) -> u32 {
+    let luma = img.to_luma();
+    let (w, h) = luma.dimensions();
+    let along_x = w > h;
+    let mut energy = vec![0u64; max(w, h) as usize];
+    for (x, y, p) in luma.enumerate_pixels() {
+        let right = luma.get_pixel(min(x + 1, w - 1), y);
+        let below = luma.get_pixel(x, min(y + 1, h - 1));
+        let gradient = (i32::from(p[0]) - i32::from(right[0])).abs()
+            + (i32::from(p[0]) - i32::from(below[0])).abs();
+        energy[if along_x { x } else { y } as usize] += gradient as u64;
+    }
+    let side = side as usize;
+    let mut window: u64 = energy[..side].iter().sum();
+    let (mut best, mut best_offset) = (window, 0);
+    for offset in 1..=energy.len() - side {
+        window = window + energy[offset + side - 1] - energy[offset - 1];
+        if window > best {
+            best = window;
+            best_offset = offset;
+        }
+    }
+    best_offset as u32
+}
+
+fn encode(img: &DynamicImage, encoding: &Encoding) -> Result<Vec<u8>, String> {
+    let mut buf = vec![];
+    match encoding.format {
+        Format::Png => img
+            .write_to(&mut buf, ImageOutputFormat::PNG)
+            .map_err(|e| format!("{}", e))?,
+        Format::Jpeg => DynamicImage::ImageRgb8(img.to_rgb())
+            .write_to(
+                &mut buf,
+                ImageOutputFormat::JPEG(encoding.quality.unwrap_or(85)),
+            )
+            .map_err(|e| format!("{}", e))?,
+        Format::Webp => {
+            let rgba = img.to_rgba();
+            let quality = f32::from(encoding.quality.unwrap_or(80));
+            buf.extend_from_slice(
+                &webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height()).encode(quality),
+            );
+        }
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::Rgb;
+
+    #[test]
+    fn test_square() {
+        let mut img = RgbaImage::from_pixel(30, 10, Rgba([0, 0, 0, 255]));
+        // detail on the right
+        for x in 20..30 {
+            for y in 0..10 {
+                if (x + y) % 2 == 0 {
+                    img.put_pixel(x, y, Rgba([255, 255, 255, 255]));
+                }
+            }
+        }
+        let img = DynamicImage::ImageRgba8(img);
+        let with = |square| AvatarConfig {
+            square,
+            background: String::from("#ff000080"),
+            ..AvatarConfig::default()
+        };
+
+        assert!(square(&img, &with(Square::Reject)).is_err());
+        let cropped = square(&img, &with(Square::Crop)).unwrap().unwrap();
+        assert_eq!(cropped.dimensions(), (10, 10));
+        assert_eq!(cropped.get_pixel(0, 0), Rgba([0, 0, 0, 255]));
+        let salient = square(&img, &with(Square::Saliency)).unwrap().unwrap();
+        assert_eq!(salient.dimensions(), (10, 10));
+        assert_eq!(salient.get_pixel(0, 0), Rgba([255, 255, 255, 255]));
+        let padded = square(&img, &with(Square::Pad)).unwrap().unwrap();
+        assert_eq!(padded.dimensions(), (30, 30));
+        assert_eq!(padded.get_pixel(0, 0), Rgba([255, 0, 0, 128]));
+        // the image is blended over the background
+        assert_eq!(padded.to_rgb().get_pixel(0, 10), &Rgb([0, 0, 0]));
+
+        let almost = DynamicImage::ImageRgba8(RgbaImage::new(100, 98));
+        assert!(square(&almost, &with(Square::Reject)).unwrap().is_none());
+        assert!(parse_color("#12345").is_err());
+        assert!(parse_color("123456").is_err());
+    }
+
+    #[test]
+    fn test_incremental() {
+        let out = std::env::temp_dir().join(format!("v2conv-avatar-{}", std::process::id()));
+        let mut buf = vec![];
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(50, 50, Rgba([0, 0, 255, 255])))
+            .write_to(&mut buf, ImageOutputFormat::PNG)
+            .unwrap();
+        let config = AvatarConfig {
+            sizes: vec![20],
+            naming: Naming::Hash,
+            ..AvatarConfig::default()
+        };
+        let a = convert_buf(&buf, &out, "a", &config).unwrap();
+        assert_eq!(a, format!("{}.png", sha256_hex(&buf)));
+        let rendition = out.join(&config.renditions(&sha256_hex(&buf))[0].path);
+
+        // unchanged sources are not converted again
+        write(&rendition, b"untouched").unwrap();
+        assert_eq!(convert_buf(&buf, &out, "b", &config).unwrap(), a);
+        assert_eq!(fs::read(&rendition).unwrap(), b"untouched");
+
+        let manifest = config.manifest(&out, vec![a.as_str()]).unwrap();
+        assert_eq!(manifest["renditions"][0]["bytes"], json!(9));
+        assert_eq!(
+            manifest["renditions"][0]["sha256"],
+            json!(sha256_hex(b"untouched"))
+        );
+
+        // but changed settings are
+        let config = AvatarConfig {
+            filter: Filter::Nearest,
+            ..config
+        };
+        convert_buf(&buf, &out, "b", &config).unwrap();
+        assert_ne!(fs::read(&rendition).unwrap(), b"untouched");
+        fs::remove_dir_all(out).unwrap();
+    }
+
+    #[test]
+    fn test_decode() {
+        // left half red, right half blue, stored on its side
+        let img = RgbaImage::from_fn(32, 16, |x, _| {
+            if x < 16 {
+                Rgba([255, 0, 0, 255])
+            } else {
+                Rgba([0, 0, 255, 255])
+            }
+        });
+        let mut jpeg = vec![];
+        DynamicImage::ImageRgba8(img)
+            .write_to(&mut jpeg, ImageOutputFormat::JPEG(95))
+            .unwrap();
+        let tiff = b"MM\x00\x2a\x00\x00\x00\x08\x00\x01\x01\x12\x00\x03\x00\x00\x00\x01\x00\x06\
+                     \x00\x00\x00\x00\x00\x00";
+        let mut app1 = vec![0xff, 0xe1, 0, (2 + 6 + tiff.len()) as u8];
+        app1.extend_from_slice(b"Exif\0\0");
+        app1.extend_from_slice(tiff);
+        let with_exif: Vec<u8> = jpeg[..2]
+            .iter()
+            .chain(&app1)
+            .chain(&jpeg[2..])
+            .cloned()
+            .collect();
+        assert_eq!(exif_orientation(&with_exif), 6);
+
+        let config = AvatarConfig::default();
+        let upright = decode(&with_exif, &config).unwrap();
+        assert_eq!(upright.dimensions(), (16, 32));
+        assert!(upright.get_pixel(8, 4)[0] > 200);
+        assert!(upright.get_pixel(8, 28)[2] > 200);
+
+        let out = std::env::temp_dir().join(format!("v2conv-decode-{}", std::process::id()));
+        let jpeg_only = AvatarConfig {
+            formats: vec![Encoding {
+                format: Format::Jpeg,
+                quality: None,
+            }],
+            ..config.clone()
+        };
+        convert_buf(&with_exif, &out, "a", &jpeg_only).unwrap();
+        let rendition = fs::read(out.join("230/a.jpg")).unwrap();
+        assert!(!rendition.windows(4).any(|w| w == b"Exif"));
+        fs::remove_dir_all(out).unwrap();
+
+        let small = AvatarConfig {
+            max_dimension: 20,
+            ..config.clone()
+        };
+        assert!(decode(&jpeg, &small).err().unwrap().contains("32x16"));
+        let few = AvatarConfig {
+            max_pixels: 100,
+            ..config.clone()
+        };
+        assert!(decode(&jpeg, &few).is_err());
+        let tiny = AvatarConfig {
+            max_bytes: 10,
+            ..config.clone()
+        };
+        assert!(decode(&jpeg, &tiny).err().unwrap().contains("bytes"));
+        assert!(decode(b"BM not an avatar", &config).is_err());
+    }
+
+    #[test]
+    fn test_renditions() {
+        let config: AvatarConfig = serde_json::from_value(json!({
+            "sizes": [512, 64],
+            "formats": [{ "format": "webp", "quality": 80 }, { "format": "jpeg" }],
+            "layout": "{name}/{size}.{ext}",
+        }))
+        .unwrap();
+        assert_eq!(config.check(), Ok(()));
+        assert_eq!(config.picture("abc"), "abc.webp");
+        let with_url = AvatarConfig {
+            base_url: Some(String::from("https://cdn.example.com/avatars/")),
+            ..config.clone()
+        };
+        assert_eq!(
+            with_url.picture("abc"),
+            "https://cdn.example.com/avatars/abc/512.webp"
+        );
+        assert_eq!(
+            with_url.name_of("https://cdn.example.com/avatars/abc/512.webp"),
+            Some(String::from("abc"))
+        );
+        assert_eq!(config.name_of("abc.webp"), Some(String::from("abc")));
+        let paths: Vec<String> = config
+            .renditions("abc")
+            .into_iter()
+            .map(|r| r.path)
+            .collect();
+        assert_eq!(
+            paths,
+            vec!["abc/512.webp", "abc/512.jpg", "abc/64.webp", "abc/64.jpg"]
+        );
+
+        let ambiguous = AvatarConfig {
+            layout: String::from("{name}.{ext}"),
+            ..AvatarConfig::default()
+        };
+        assert!(ambiguous.check().is_err());
+        assert_eq!(
+            AvatarConfig::default().renditions("abc")[0].path,
+            "230/abc.png"
+        );
+    }
 }
@@ -0,0 +1,267 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::mpsc::channel;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use reqwest::header::{CONTENT_LENGTH, ETAG, IF_NONE_MATCH};
+use reqwest::{Client, StatusCode};
+
+use avatar::AvatarConfig;
+use retry::{delay, retry_after};
+use writer::{sha256_hex, write};
+
+/// Downloaded bodies or why the download failed, keyed by URL.
+pub type Downloads = HashMap<String, Result<Vec<u8>, String>>;
+
+/// The pictures prefetched for a batch of profiles. Others are downloaded
+/// when asked for.
+pub struct Pictures<'a> {
+    pub downloader: Option<&'a Downloader>,
+    pub downloads: Downloads,
+}
+
+impl<'a> Pictures<'a> {
+    pub fn get(&self, url: &str) -> Result<Cow<'_, [u8]>, String> {
+        match (self.downloads.get(url), self.downloader) {
+            (Some(Ok(body)), _) => Ok(Cow::Borrowed(body)),
+            (Some(Err(e)), _) => Err(e.clone()),
+            (None, Some(downloader)) => downloader.fetch(url).map(Cow::Owned),
+            (None, None) => Err(format!("{}: not downloaded", url)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DownloadConfig {
+    pub workers: usize,
+    pub timeout: Duration,
+    /// Attempts after the first one for timeouts, connection errors, 429 and
+    /// 5xx responses.
+    pub retries: u32,
+    /// Wait before the first retry, doubled for every further one, unless
+    /// the response has a `Retry-After`. No wait is longer than `timeout`.
+    pub backoff: Duration,
+    pub max_bytes: u64,
+    /// Directory for bodies and their ETags. Cached bodies are revalidated
+    /// with `If-None-Match`.
+    pub cache: Option<PathBuf>,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        DownloadConfig {
+            workers: 8,
+            timeout: Duration::from_secs(10),
+            retries: 3,
+            backoff: Duration::from_millis(500),
+            max_bytes: AvatarConfig::default().max_bytes,
+            cache: None,
+        }
+    }
+}
+
+enum Failure {
+    Retry(String, Option<Duration>),
+    Fatal(String),
+}
+
+pub struct Downloader {
+    client: Client,
+    config: DownloadConfig,
+}
+
+impl Downloader {
+    pub fn new(config: DownloadConfig) -> Result<Self, String> {
+        if let Some(cache) = &config.cache {
+            fs::create_dir_all(cache).map_err(|e| format!("{}: {}", cache.display(), e))?;
+        }
+        let client = Client::builder()
+            .timeout(config.timeout)
+            .build()
+            .map_err(|e| format!("{}", e))?;
+        Ok(Downloader { client, config })
+    }
+
+    /// Downloads `urls` with `config.workers` downloads at a time.
+    pub fn fetch_all(&self, urls: impl IntoIterator<Item = String>) -> Downloads {
+        let mut queue: Vec<String> = urls.into_iter().collect();
+        queue.sort();
+        queue.dedup();
+        queue.reverse();
+        let queue = Mutex::new(queue);
+        let (tx, rx) = channel();
+        thread::scope(|s| {
+            for _ in 0..self.config.workers.max(1) {
+                let tx = tx.clone();
+                let queue = &queue;
+                s.spawn(move || loop {
+                    let url = match queue.lock().expect("download queue").pop() {
+                        Some(url) => url,
+                        None => break,
+                    };
+                    let result = self.fetch(&url);
+                    if tx.send((url, result)).is_err() {
+                        break;
+                    }
+                });
+            }
+        });
+        drop(tx);
+        rx.into_iter().collect()
+    }
+
+    pub fn fetch(&self, url: &str) -> Result<Vec<u8>, String> {
+        let mut attempt = 0;
+        loop {
+            match self.attempt(url) {
+                Ok(body) => return Ok(body),
+                Err(Failure::Retry(e, wait)) if attempt < self.config.retries => {
+                    let c = &self.config;
+                    thread::sleep(delay(wait, c.backoff, attempt, c.timeout));
+                    attempt += 1;
+                    eprintln!("retrying {} ({})", url, e);
+                }
+                Err(Failure::Retry(e, _)) | Err(Failure::Fatal(e)) => {
+                    return Err(format!("{}: {}", url, e))
+                }
+            }
+        }
+    }
+
+    fn cache_paths(&self, url: &str) -> Option<(PathBuf, PathBuf)> {
+        self.config.cache.as_ref().map(|cache| {
+            let key = sha256_hex(url.as_bytes());
+            (
+                cache.join(format!("{}.body", key)),
+                cache.join(format!("{}.etag", key)),
+            )
+        })
+    }
+
+    fn attempt(&self, url: &str) -> Result<Vec<u8>, Failure> {
+        let cached = self.cache_paths(url).and_then(|(body, etag)| {
+            match (fs::read(body), fs::read_to_string(etag)) {
+                (Ok(body), Ok(etag)) => Some((body, etag)),
+                _ => None,
+            }
+        });
+        let mut request = self.client.get(url);
+        if let Some((_, etag)) = &cached {
+            request = request.header(IF_NONE_MATCH, etag.as_str());
+        }
+        let resp = request
+            .send()
+            .map_err(|e| Failure::Retry(format!("{}", e), None))?;
+        let status = resp.status();
+        if status == StatusCode::NOT_MODIFIED {
+            if let Some((body, _)) = cached {
+                return Ok(body);
+            }
+        }
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            return Err(Failure::Retry(
+                format!("status {}", status),
+                retry_after(resp.headers()),
+            ));
+        }
+        if !status.is_success() {
+            return Err(Failure::Fatal(format!("status {}", status)));
+        }
+        let too_large = || Failure::Fatal(format!("larger than {} bytes", self.config.max_bytes));
+        let length = resp
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        if length
+            .map(|l| l > self.config.max_bytes)
+            .unwrap_or_default()
+        {
+            return Err(too_large());
+        }
+        let etag = resp
+            .headers()
+            .get(ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
+        let mut body = vec![];
+        resp.take(self.config.max_bytes + 1)
+            .read_to_end(&mut body)
+            .map_err(|e| Failure::Retry(format!("{}", e), None))?;
+        if body.len() as u64 > self.config.max_bytes {
+            return Err(too_large());
+        }
+        if let (Some((body_path, etag_path)), Some(etag)) = (self.cache_paths(url), etag) {
+            let cached = write(&body_path, &body).and_then(|_| write(&etag_path, etag.as_bytes()));
+            if let Err(e) = cached {
+                eprintln!("error caching {}: {}", url, e);
+            }
+        }
+        Ok(body)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mock::{response, serve};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_fetch() {
+        // `/flaky` answers with a 503 first, then with a body and an ETag
+        let bodies = Arc::new(Mutex::new(0));
+        let counter = bodies.clone();
+        let mut flaky = 0;
+        let base = serve(move |r| match r.path.as_str() {
+            "/flaky" => {
+                flaky += 1;
+                if flaky == 1 {
+                    response("503 Service Unavailable", &[], "")
+                } else if r.header("if-none-match") == "\"v1\"" {
+                    response("304 Not Modified", &["ETag: \"v1\""], "")
+                } else {
+                    *counter.lock().unwrap() += 1;
+                    response("200 OK", &["ETag: \"v1\""], "hello")
+                }
+            }
+            "/big" => response("200 OK", &[], &"x".repeat(100)),
+            _ => response("404 Not Found", &[], ""),
+        });
+        let cache = std::env::temp_dir().join(format!("v2conv-download-{}", std::process::id()));
+        let downloader = Downloader::new(DownloadConfig {
+            workers: 2,
+            backoff: Duration::from_millis(1),
+            max_bytes: 50,
+            cache: Some(cache.clone()),
+            ..DownloadConfig::default()
+        })
+        .unwrap();
+        let urls = vec![
+            format!("{}/flaky", base),
+            format!("{}/big", base),
+            format!("{}/missing", base),
+        ];
+        let downloads = downloader.fetch_all(urls.clone());
+        assert_eq!(downloads[&urls[0]], Ok(b"hello".to_vec()));
+        assert!(downloads[&urls[1]]
+            .as_ref()
+            .unwrap_err()
+            .contains("larger than 50"));
+        assert!(downloads[&urls[2]].as_ref().unwrap_err().contains("404"));
+
+        // answered from the cache after a 304
+        let pictures = Pictures {
+            downloader: Some(&downloader),
+            downloads: Downloads::new(),
+        };
+        assert_eq!(pictures.get(&urls[0]), Ok(Cow::from(&b"hello"[..])));
+        assert_eq!(*bodies.lock().unwrap(), 1);
+        fs::remove_dir_all(cache).unwrap();
+    }
+}
@@ -0,0 +1,170 @@
+//! Consistency checks between the hand written `schema::Profile` and the
+//! bundled `schema.json`.
+
+use std::collections::BTreeMap;
+
+use serde_json::Value;
+
+use attributes::{to_value, walk};
+use schema::Profile;
+use timestamp::finish;
+use validate::Validator;
+
+/// Display and classification values `schema.json` allows for an attribute.
+/// `None` means unconstrained.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Constraints {
+    pub display: Option<Vec<Value>>,
+    pub classification: Option<Vec<Value>>,
+}
+
+/// All attributes defined by `schema.json` with their constraints, keyed by
+/// JSON pointer. Constraints on groups like `staff_information` apply to
+/// their attributes unless those define their own.
+pub fn expected(validator: &Validator) -> BTreeMap<String, Constraints> {
+    let mut out = BTreeMap::new();
+    if let Some(profile) = validator.resolve("#/definitions/Profile") {
+        collect(validator, profile, "", &Constraints::default(), &mut out);
+    }
+    out
+}
+
+fn collect(
+    validator: &Validator,
+    schema: &Value,
+    path: &str,
+    inherited: &Constraints,
+    out: &mut BTreeMap<String, Constraints>,
+) {
+    let mut nodes = vec![];
+    flatten(validator, schema, &mut nodes);
+    let constraints = Constraints {
+        display: intersect(&nodes, "display").or_else(|| inherited.display.clone()),
+        classification: intersect(&nodes, "classification")
+            .or_else(|| inherited.classification.clone()),
+    };
+    let mut properties = BTreeMap::new();
+    for n in &nodes {
+        if let Some(o) = n["properties"].as_object() {
+            for (k, s) in o {
+                properties.entry(k.clone()).or_insert_with(Vec::new).push(s);
+            }
+        }
+    }
+    if properties.contains_key("metadata") && properties.contains_key("signature") {
+        out.insert(String::from(path), constraints);
+        return;
+    }
+    for (k, schemas) in properties {
+        if k == "metadata" {
+            continue;
+        }
+        let merged = json!({ "allOf": schemas });
+        collect(
+            validator,
+            &merged,
+            &format!("{}/{}", path, k),
+            &constraints,
+            out,
+        );
+    }
+}
+
+/// All schemas `schema` is made of through `$ref` and `allOf`.
+fn flatten<'a>(validator: &'a Validator, schema: &'a Value, nodes: &mut Vec<&'a Value>) {
+    if let Some(r) = schema["$ref"].as_str() {
+        if let Some(s) = validator.resolve(r) {
+            flatten(validator, s, nodes);
+        }
+        return;
+    }
+    nodes.push(schema);
+    if let Some(all) = schema["allOf"].as_array() {
+        for s in all {
+            flatten(validator, s, nodes);
+        }
+    }
+}
+
+fn intersect(nodes: &[&Value], field: &str) -> Option<Vec<Value>> {
+    nodes
+        .iter()
+        .filter_map(|n| n["properties"]["metadata"]["properties"][field]["enum"].as_array())
+        .fold(None, |acc: Option<Vec<Value>>, e| match acc {
+            None => Some(e.clone()),
+            Some(acc) => Some(acc.into_iter().filter(|v| e.contains(v)).collect()),
+        })
+}
+
+/// Differences between `schema.json` and the defaults of `schema::Profile`.
+pub fn check(validator: &Validator) -> Vec<String> {
+    let mut drift = vec![];
+    let p = finish(
+        Profile::default(),
+        "1970-01-01T00:00:00Z",
+        "1970-01-01T00:00:00Z",
+    )
+    .and_then(|p| to_value(&p))
+    .expect("default profile");
+    for v in validator.validate(&p) {
+        drift.push(format!("{}: {}", v.pointer, v.message));
+    }
+    let expected = expected(validator);
+    walk(&p, &mut |path, _| {
+        if !expected.contains_key(path) {
+            drift.push(format!("{}: not in schema.json", path));
+        }
+    });
+    for (path, constraints) in &expected {
+        let a = match p.pointer(path) {
+            Some(a) => a,
+            None => {
+                drift.push(format!("{}: not in schema.rs", path));
+                continue;
+            }
+        };
+        for (field, allowed) in &[
+            ("display", &constraints.display),
+            ("classification", &constraints.classification),
+        ] {
+            let actual = &a["metadata"][field];
+            if let Some(allowed) = allowed {
+                if !allowed.contains(actual) {
+                    drift.push(format!(
+                        "{}: default {} {} is not one of {}",
+                        path,
+                        field,
+                        actual,
+                        json!(allowed)
+                    ));
+                }
+            }
+        }
+    }
+    drift
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_expected() {
+        let expected = expected(&Validator::bundled());
+        assert_eq!(expected["/usernames"].display, Some(vec![json!("public")]));
+        assert_eq!(
+            expected["/staff_information/team"].classification,
+            Some(vec![json!("MOZILLA CONFIDENTIAL")])
+        );
+        assert_eq!(
+            expected["/staff_information/cost_center"].classification,
+            Some(vec![json!("WORKGROUP CONFIDENTIAL: STAFF ONLY")])
+        );
+    }
+
+    #[test]
+    fn test_no_drift() {
+        let drift = check(&Validator::bundled());
+        assert!(drift.is_empty(), "schema drift:\n{}", drift.join("\n"));
+    }
+}
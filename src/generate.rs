@@ -0,0 +1,328 @@
+use image::{ImageBuffer, Rgb, RgbImage};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use serde_json::{Map, Value};
+
+use anonymize::{FIRST_NAMES, LAST_NAMES, LOCATIONS, TITLES};
+use tz::HRIS_TIMEZONES;
+
+const TEAMS: &[&str] = &[
+    "IAM",
+    "Firefox",
+    "Infrastructure",
+    "Security",
+    "Marketing",
+    "Legal",
+];
+const WORKER_TYPES: &[&str] = &["Employee", "Contractor", "Intern"];
+const LANGUAGES: &[&str] = &["de", "en", "fr", "ja", "pt"];
+const TAGS: &[&str] = &["rust", "python", "iam", "l10n", "design", "community"];
+/// Not known to `tz::from_hris`.
+const UNKNOWN_TIMEZONE: &str = "GMT+05:45 Nepal Time (Kathmandu)";
+
+/// Which sources know about a generated person.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    /// In HRIS and LDAP.
+    Staff,
+    /// In HRIS, LDAP and mozillians.
+    StaffMozillian,
+    /// Only in mozillians.
+    Mozillian,
+}
+
+/// Inputs `merge` has to cope with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EdgeCase {
+    MissingFields,
+    NonSquareAvatar,
+    UnknownTimezone,
+}
+
+const EDGE_CASES: &[EdgeCase] = &[
+    EdgeCase::MissingFields,
+    EdgeCase::NonSquareAvatar,
+    EdgeCase::UnknownTimezone,
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Options {
+    pub count: usize,
+    /// Share of people only in HRIS and LDAP.
+    pub staff: f64,
+    /// Share of people in HRIS and LDAP who also have a mozillians profile.
+    /// The rest of the people are only in mozillians.
+    pub staff_mozillians: f64,
+    /// Probability of a person hitting one of the edge cases.
+    pub edge_cases: f64,
+    pub seed: u64,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            count: 100,
+            staff: 0.5,
+            staff_mozillians: 0.3,
+            edge_cases: 0.1,
+            seed: 0,
+        }
+    }
+}
+
+/// Source files in the formats `load_all` reads, plus the LDAP photos
+/// keyed by file name.
+pub struct Generated {
+    pub hris: Value,
+    pub ldap: Value,
+    pub mozillians: Value,
+    pub avatars: Vec<(String, RgbImage)>,
+}
+
+impl Options {
+    pub fn check(&self) -> Result<(), String> {
+        let ratios = [self.staff, self.staff_mozillians, self.edge_cases];
+        if ratios.iter().any(|r| !(0.0..=1.0).contains(r)) {
+            return Err(String::from("ratios must be between 0 and 1"));
+        }
+        if self.staff + self.staff_mozillians > 1.0 {
+            return Err(String::from("staff ratios must not add up to more than 1"));
+        }
+        Ok(())
+    }
+
+    /// The kind of every person, in order.
+    pub fn kinds(&self) -> Vec<Kind> {
+        let staff = (self.count as f64 * self.staff).round() as usize;
+        let both = ((self.count as f64 * self.staff_mozillians).round() as usize)
+            .min(self.count - staff.min(self.count));
+        (0..self.count)
+            .map(|i| match i {
+                i if i < staff => Kind::Staff,
+                i if i < staff + both => Kind::StaffMozillian,
+                _ => Kind::Mozillian,
+            })
+            .collect()
+    }
+}
+
+/// Generates `options.count` people. The same options always produce the
+/// same data.
+pub fn generate(options: &Options) -> Result<Generated, String> {
+    options.check()?;
+    let mut rng = StdRng::seed_from_u64(options.seed);
+    let mut out = Generated {
+        hris: json!({ "Report_Entry": [] }),
+        ldap: Value::Object(Map::new()),
+        mozillians: json!([]),
+        avatars: vec![],
+    };
+    for (i, kind) in options.kinds().into_iter().enumerate() {
+        let edge_case = if rng.gen_bool(options.edge_cases) {
+            EDGE_CASES.choose(&mut rng).cloned()
+        } else {
+            None
+        };
+        person(&mut rng, i, kind, edge_case, &mut out);
+    }
+    Ok(out)
+}
+
+fn pick<R: Rng>(rng: &mut R, list: &[&str]) -> String {
+    String::from(*list.choose(rng).expect("non empty list"))
+}
+
+fn some<R: Rng>(rng: &mut R, list: &[&str]) -> Vec<String> {
+    list.iter()
+        .filter(|_| rng.gen_bool(0.3))
+        .map(|s| String::from(*s))
+        .collect()
+}
+
+fn person<R: Rng>(
+    rng: &mut R,
+    i: usize,
+    kind: Kind,
+    edge_case: Option<EdgeCase>,
+    out: &mut Generated,
+) {
+    let first_name = pick(rng, FIRST_NAMES);
+    let last_name = pick(rng, LAST_NAMES);
+    let uid = format!("{}{}{}", &first_name[..1], last_name, i).to_lowercase();
+    let missing = edge_case == Some(EdgeCase::MissingFields);
+    let hire_date = format!(
+        "{}-{:02}-{:02}",
+        rng.gen_range(2005, 2019),
+        rng.gen_range(1, 13),
+        rng.gen_range(1, 29)
+    );
+    let (email, user_id) = match kind {
+        Kind::Mozillian => (
+            format!("{}@example.com", uid),
+            format!("github|{}", 100_000 + i),
+        ),
+        _ => (
+            format!("{}@mozilla.com", uid),
+            format!("ad|Mozilla-LDAP|{}", uid),
+        ),
+    };
+
+    if kind != Kind::Mozillian {
+        let timezone = if edge_case == Some(EdgeCase::UnknownTimezone) {
+            String::from(UNKNOWN_TIMEZONE)
+        } else {
+            String::from(HRIS_TIMEZONES.choose(rng).expect("time zones").0)
+        };
+        let mut hris = json!({
+            "PrimaryWorkEmail": email,
+            "CurrentlyActive": "1",
+            "Cost_Center": format!("{} - {}", rng.gen_range(1000, 9999), pick(rng, TEAMS)),
+            "isDirectorOrAbove": if rng.gen_bool(0.05) { "TRUE" } else { "FALSE" },
+            "IsManager": if rng.gen_bool(0.2) { "TRUE" } else { "FALSE" },
+            "LocationDescription": pick(rng, LOCATIONS),
+            "EmployeeID": format!("{}", 10_000 + i),
+            "Team": pick(rng, TEAMS),
+            "businessTitle": format!("{} {}", pick(rng, TITLES), rng.gen_range(1, 5)),
+            "WorkerType": pick(rng, WORKER_TYPES),
+            "WPRDeskNumber": format!("{}", rng.gen_range(1, 1000)),
+            "Time_Zone": timezone,
+            "Hire_Date": hire_date,
+        });
+        if missing {
+            if let Some(o) = hris.as_object_mut() {
+                for k in &["Team", "Time_Zone", "Hire_Date", "LocationDescription"] {
+                    o.remove(*k);
+                }
+            }
+        }
+        if let Some(a) = out.hris["Report_Entry"].as_array_mut() {
+            a.push(hris);
+        }
+
+        let picture = format!("{}.png", uid);
+        let (w, h) = if edge_case == Some(EdgeCase::NonSquareAvatar) {
+            (320, 200)
+        } else {
+            (256, 256)
+        };
+        let color = Rgb([rng.gen(), rng.gen(), rng.gen()]);
+        out.avatars
+            .push((picture.clone(), ImageBuffer::from_pixel(w, h, color)));
+        let mut usernames = Map::new();
+        if rng.gen_bool(0.5) {
+            usernames.insert(String::from("LDAP-1"), json!(format!("IRC: {}", uid)));
+        }
+        let ldap = json!({
+            "primary_email": { "value": email },
+            "user_id": { "value": user_id },
+            "first_name": { "value": if missing { None } else { Some(&first_name) } },
+            "last_name": { "value": last_name },
+            "ssh_public_keys": { "values": {
+                "key1": format!("ssh-ed25519 AAAA{} {}@host", uid, uid)
+            } },
+            "pgp_public_keys": { "values": {} },
+            "phone_numbers": { "values": {} },
+            "identities": {},
+            "usernames": { "values": usernames },
+            "login_method": { "value": "Mozilla-LDAP" },
+            "access_information": { "ldap": { "values": { "team_moco": null } } },
+            "fun_title": { "value": null },
+            "active": { "value": true },
+            "description": { "value": null },
+            "picture": { "value": format!("/photos/{}", picture) },
+        });
+        if let Some(o) = out.ldap.as_object_mut() {
+            o.insert(format!("mail={},o=com,dc=mozilla", email), ldap);
+        }
+    }
+
+    if kind == Kind::Staff {
+        return;
+    }
+    let timezone = HRIS_TIMEZONES.choose(rng).expect("time zones").1.name();
+    let mozillian = json!({
+        "username": uid,
+        "user_id": user_id,
+        "first_name": first_name,
+        "last_name": if missing { None } else { Some(&last_name) },
+        "fun_title": null,
+        "description": null,
+        "location_preference": pick(rng, LOCATIONS),
+        "timezone": timezone,
+        "access_information": {},
+        "tags": some(rng, TAGS),
+        "skills": some(rng, TAGS),
+        "preferred_language": some(rng, LANGUAGES),
+        "uris": {},
+        "picture": null,
+        "idps": [{ "email": email }],
+        "date_joined": format!("{}T12:00:00", hire_date),
+    });
+    if let Some(a) = out.mozillians.as_array_mut() {
+        a.push(mozillian);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_generate() {
+        let options = Options {
+            count: 10,
+            staff: 0.5,
+            staff_mozillians: 0.2,
+            edge_cases: 0.0,
+            seed: 42,
+        };
+        let g = generate(&options).unwrap();
+        assert_eq!(g.hris["Report_Entry"].as_array().unwrap().len(), 7);
+        assert_eq!(g.ldap.as_object().unwrap().len(), 7);
+        assert_eq!(g.mozillians.as_array().unwrap().len(), 5);
+        assert_eq!(g.avatars.len(), 7);
+        let again = generate(&options).unwrap();
+        assert_eq!(g.hris, again.hris);
+        assert_eq!(g.ldap, again.ldap);
+        assert_eq!(g.mozillians, again.mozillians);
+
+        let staff_mozillian = &g.mozillians[0];
+        let ldap = g
+            .ldap
+            .as_object()
+            .unwrap()
+            .values()
+            .find(|l| l["user_id"]["value"] == staff_mozillian["user_id"]);
+        assert!(ldap.is_some());
+    }
+
+    #[test]
+    fn test_edge_cases() {
+        let options = Options {
+            count: 50,
+            staff: 1.0,
+            staff_mozillians: 0.0,
+            edge_cases: 1.0,
+            seed: 1,
+        };
+        let g = generate(&options).unwrap();
+        assert!(g.avatars.iter().any(|(_, img)| img.width() != img.height()));
+        assert!(g.hris["Report_Entry"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|h| h["Time_Zone"] == json!(UNKNOWN_TIMEZONE)));
+        assert!(g.hris["Report_Entry"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|h| h.get("Team").is_none()));
+        assert!(generate(&Options {
+            staff: 0.8,
+            staff_mozillians: 0.8,
+            ..Options::default()
+        })
+        .is_err());
+    }
+}
@@ -1,12 +1,94 @@
+use std::collections::{BTreeMap, BTreeSet};
+
 use regex::Captures;
 use regex::Regex;
 use serde_json::Value;
 
+use loader::load_json;
 use tz::from_hris;
 use schema::*;
 
-pub fn map_hris(mut p2: Profile, hris: &Value) -> Profile {
-    p2.access_information.hris.values = hris.clone();
+/// Columns copied into `access_information.hris` unless configured otherwise.
+const DEFAULT_FIELDS: &[&str] = &[
+    "Cost_Center",
+    "CurrentlyActive",
+    "EmployeeID",
+    "Hire_Date",
+    "IsManager",
+    "LocationDescription",
+    "PrimaryWorkEmail",
+    "Team",
+    "Time_Zone",
+    "WPRDeskNumber",
+    "WorkerType",
+    "businessTitle",
+    "isDirectorOrAbove",
+];
+
+/// Which HRIS columns end up in `access_information.hris`.
+///
+/// Loaded from a JSON file like
+/// `{ "allow": { "EmployeeID": null, "Cost_Center": "cost_center" }, "ignore": ["SSN"] }`
+/// where `allow` maps each kept column to an optional new name and `ignore`
+/// lists columns which are left out without being reported.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct HrisFields {
+    allow: BTreeMap<String, Option<String>>,
+    #[serde(default)]
+    ignore: BTreeSet<String>,
+}
+
+impl Default for HrisFields {
+    fn default() -> Self {
+        HrisFields {
+            allow: DEFAULT_FIELDS
+                .iter()
+                .map(|f| (String::from(*f), None))
+                .collect(),
+            ignore: BTreeSet::default(),
+        }
+    }
+}
+
+impl HrisFields {
+    pub fn load(path: &str) -> Result<Self, String> {
+        serde_json::from_value(load_json(path)?).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// The allowed columns of a record, renamed.
+    pub fn select(&self, hris: &Value) -> Value {
+        let mut values = serde_json::Map::new();
+        if let Some(o) = hris.as_object() {
+            for (k, v) in o {
+                if let Some(rename) = self.allow.get(k) {
+                    values.insert(rename.clone().unwrap_or_else(|| k.clone()), v.clone());
+                }
+            }
+        }
+        Value::Object(values)
+    }
+
+    /// Counts the columns of `records` which are neither allowed nor ignored.
+    pub fn unexpected<'a>(
+        &self,
+        records: impl IntoIterator<Item = &'a Value>,
+    ) -> BTreeMap<String, usize> {
+        let mut unexpected = BTreeMap::new();
+        for k in records
+            .into_iter()
+            .filter_map(Value::as_object)
+            .flat_map(|o| o.keys())
+        {
+            if !self.allow.contains_key(k) && !self.ignore.contains(k) {
+                *unexpected.entry(k.clone()).or_insert(0) += 1;
+            }
+        }
+        unexpected
+    }
+}
+
+pub fn map_hris(mut p2: Profile, hris: &Value, fields: &HrisFields) -> Profile {
+    p2.access_information.hris.values = fields.select(hris);
 
     p2.staff_information.cost_center.value = hris["Cost_Center"].as_str().map(String::from);
     p2.staff_information.director.value = hris["isDirectorOrAbove"]
@@ -34,7 +116,7 @@ pub fn map_hris(mut p2: Profile, hris: &Value) -> Profile {
 
 fn censor_title(title: &str) -> String {
     let re = Regex::new(r#"((Management)|(Engineering)|(Development))(:? Mgmt \d$)"#).unwrap();
-    let censored = re.replace(&title, |caps: &Captures| caps[1].to_string());
+    let censored = re.replace(title, |caps: &Captures| caps[1].to_string());
     let re = Regex::new(r#"(:? Mgmt \d$)"#).unwrap();
     let censored = re.replace(&censored, " Management");
     let re = Regex::new(r#" \d$"#).unwrap();
@@ -45,6 +127,29 @@ fn censor_title(title: &str) -> String {
 #[cfg(test)]
 mod test {
     use super::censor_title;
+    use super::HrisFields;
+    #[test]
+    fn test_fields() {
+        let fields: HrisFields = serde_json::from_value(json!({
+            "allow": { "EmployeeID": null, "Cost_Center": "cost_center" },
+            "ignore": ["SSN"]
+        })).unwrap();
+        let hris = json!({
+            "EmployeeID": "42",
+            "Cost_Center": "1234",
+            "SSN": "xxx",
+            "Salary": "yyy"
+        });
+        assert_eq!(
+            fields.select(&hris),
+            json!({ "EmployeeID": "42", "cost_center": "1234" })
+        );
+        let unexpected = fields.unexpected(vec![&hris, &hris]);
+        assert_eq!(
+            unexpected.into_iter().collect::<Vec<_>>(),
+            vec![(String::from("Salary"), 2)]
+        );
+    }
     #[test]
     fn test_censor() {
         let title = "Foo Engineering Mgmt 5";
@@ -0,0 +1,400 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+
+use base64::{decode_config, encode_config, URL_SAFE_NO_PAD};
+use openssl::bn::BigNum;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::{Id, PKey, Private, Public};
+use openssl::rsa::Rsa;
+use openssl::sign::{Signer, Verifier};
+use serde_json::Value;
+
+use attributes::{from_value, to_value, walk_mut};
+use publisher::{parse_publisher, publisher_name};
+use schema::{Alg, Profile, PublisherAuthority};
+
+pub enum Key {
+    Hmac(Vec<u8>),
+    Rsa(PKey<Private>),
+    Ed25519(PKey<Private>),
+}
+
+pub enum PublicKey {
+    Hmac(Vec<u8>),
+    Rsa(PKey<Public>),
+    Ed25519(PKey<Public>),
+}
+
+pub fn read_key(path: &str) -> Result<Vec<u8>, String> {
+    let mut buf = vec![];
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut buf))
+        .map_err(|e| format!("{}: {}", path, e))?;
+    Ok(buf)
+}
+
+/// Reads a secret from `path` or, if not given, from the environment
+/// variable `env`. A trailing newline is not part of the secret.
+pub fn read_secret(path: Option<&str>, env: &str) -> Result<Vec<u8>, String> {
+    let mut secret = match path {
+        Some(path) => read_key(path)?,
+        None => env::var(env)
+            .map(String::into_bytes)
+            .map_err(|_| format!("a secret file or ${} is needed", env))?,
+    };
+    let len = secret
+        .iter()
+        .rposition(|b| *b != b'\n' && *b != b'\r')
+        .map_or(0, |i| i + 1);
+    secret.truncate(len);
+    if secret.is_empty() {
+        return Err(match path {
+            Some(path) => format!("{}: the secret is empty", path),
+            None => format!("${} is empty", env),
+        });
+    }
+    Ok(secret)
+}
+
+fn parse_jwk(buf: &[u8]) -> Result<Value, String> {
+    serde_json::from_slice(buf).map_err(|e| format!("{}", e))
+}
+
+impl Key {
+    /// Loads a private key from a PEM (RSA or Ed25519) or JWK (oct, RSA or
+    /// OKP) file.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let buf = read_key(path)?;
+        if buf.starts_with(b"-----BEGIN") {
+            Key::from_pem(&buf)
+        } else {
+            parse_jwk(&buf).and_then(|jwk| Key::from_jwk(&jwk))
+        }
+        .map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn from_pem(pem: &[u8]) -> Result<Self, String> {
+        let pkey = PKey::private_key_from_pem(pem).map_err(|e| format!("{}", e))?;
+        match pkey.id() {
+            Id::RSA => Ok(Key::Rsa(pkey)),
+            Id::ED25519 => Ok(Key::Ed25519(pkey)),
+            id => Err(format!("unsupported key type: {:?}", id)),
+        }
+    }
+
+    pub fn from_jwk(jwk: &Value) -> Result<Self, String> {
+        match jwk["kty"].as_str() {
+            Some("oct") => Ok(Key::Hmac(jwk_param(jwk, "k")?)),
+            Some("RSA") => {
+                let n = |p| jwk_param(jwk, p).and_then(|b| bignum(&b));
+                let rsa = Rsa::from_private_components(
+                    n("n")?,
+                    n("e")?,
+                    n("d")?,
+                    n("p")?,
+                    n("q")?,
+                    n("dp")?,
+                    n("dq")?,
+                    n("qi")?,
+                )
+                .map_err(|e| format!("{}", e))?;
+                PKey::from_rsa(rsa)
+                    .map(Key::Rsa)
+                    .map_err(|e| format!("{}", e))
+            }
+            Some("OKP") if jwk["crv"].as_str() == Some("Ed25519") => {
+                PKey::private_key_from_raw_bytes(&jwk_param(jwk, "d")?, Id::ED25519)
+                    .map(Key::Ed25519)
+                    .map_err(|e| format!("{}", e))
+            }
+            _ => Err(format!("unsupported jwk: {}", jwk["kty"])),
+        }
+    }
+
+    pub fn alg(&self) -> Alg {
+        match self {
+            Key::Hmac(_) => Alg::Hs256,
+            Key::Rsa(_) => Alg::Rs256,
+            Key::Ed25519(_) => Alg::Ed25519,
+        }
+    }
+
+    pub fn public(&self) -> Result<PublicKey, String> {
+        let public = |pkey: &PKey<Private>| {
+            pkey.public_key_to_pem()
+                .and_then(|pem| PKey::public_key_from_pem(&pem))
+                .map_err(|e| format!("{}", e))
+        };
+        match self {
+            Key::Hmac(secret) => Ok(PublicKey::Hmac(secret.clone())),
+            Key::Rsa(pkey) => public(pkey).map(PublicKey::Rsa),
+            Key::Ed25519(pkey) => public(pkey).map(PublicKey::Ed25519),
+        }
+    }
+
+    pub fn sign(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let signature = match self {
+            Key::Hmac(secret) => PKey::hmac(secret).and_then(|pkey| {
+                let mut signer = Signer::new(MessageDigest::sha256(), &pkey)?;
+                signer.update(data)?;
+                signer.sign_to_vec()
+            }),
+            Key::Rsa(pkey) => Signer::new(MessageDigest::sha256(), pkey)
+                .and_then(|mut signer| signer.sign_oneshot_to_vec(data)),
+            Key::Ed25519(pkey) => Signer::new_without_digest(pkey)
+                .and_then(|mut signer| signer.sign_oneshot_to_vec(data)),
+        };
+        signature.map_err(|e| format!("{}", e))
+    }
+}
+
+impl PublicKey {
+    /// Loads a key for verification. Private keys are accepted as well.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let buf = read_key(path)?;
+        if buf.starts_with(b"-----BEGIN PUBLIC KEY") {
+            PublicKey::from_pem(&buf)
+        } else if buf.starts_with(b"-----BEGIN") {
+            Key::from_pem(&buf).and_then(|k| k.public())
+        } else {
+            parse_jwk(&buf).and_then(|jwk| PublicKey::from_jwk(&jwk))
+        }
+        .map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn from_pem(pem: &[u8]) -> Result<Self, String> {
+        let pkey = PKey::public_key_from_pem(pem).map_err(|e| format!("{}", e))?;
+        match pkey.id() {
+            Id::RSA => Ok(PublicKey::Rsa(pkey)),
+            Id::ED25519 => Ok(PublicKey::Ed25519(pkey)),
+            id => Err(format!("unsupported key type: {:?}", id)),
+        }
+    }
+
+    pub fn from_jwk(jwk: &Value) -> Result<Self, String> {
+        if jwk["kty"].as_str() == Some("oct") || !jwk["d"].is_null() {
+            return Key::from_jwk(jwk).and_then(|k| k.public());
+        }
+        match jwk["kty"].as_str() {
+            Some("RSA") => {
+                let n = |p| jwk_param(jwk, p).and_then(|b| bignum(&b));
+                Rsa::from_public_components(n("n")?, n("e")?)
+                    .and_then(PKey::from_rsa)
+                    .map(PublicKey::Rsa)
+                    .map_err(|e| format!("{}", e))
+            }
+            Some("OKP") if jwk["crv"].as_str() == Some("Ed25519") => {
+                PKey::public_key_from_raw_bytes(&jwk_param(jwk, "x")?, Id::ED25519)
+                    .map(PublicKey::Ed25519)
+                    .map_err(|e| format!("{}", e))
+            }
+            _ => Err(format!("unsupported jwk: {}", jwk["kty"])),
+        }
+    }
+
+    pub fn alg(&self) -> Alg {
+        match self {
+            PublicKey::Hmac(_) => Alg::Hs256,
+            PublicKey::Rsa(_) => Alg::Rs256,
+            PublicKey::Ed25519(_) => Alg::Ed25519,
+        }
+    }
+
+    pub fn verify(&self, data: &[u8], signature: &[u8]) -> Result<bool, String> {
+        let valid = match self {
+            PublicKey::Hmac(secret) => Key::Hmac(secret.clone())
+                .sign(data)
+                .map(|s| s.len() == signature.len() && memcmp::eq(&s, signature))?,
+            PublicKey::Rsa(pkey) => Verifier::new(MessageDigest::sha256(), pkey)
+                .and_then(|mut verifier| verifier.verify_oneshot(signature, data))
+                .map_err(|e| format!("{}", e))?,
+            PublicKey::Ed25519(pkey) => Verifier::new_without_digest(pkey)
+                .and_then(|mut verifier| verifier.verify_oneshot(signature, data))
+                .map_err(|e| format!("{}", e))?,
+        };
+        Ok(valid)
+    }
+}
+
+/// The `alg` header value registered for JWS.
+pub fn jws_alg(alg: &Alg) -> &'static str {
+    match alg {
+        Alg::Hs256 => "HS256",
+        Alg::Rs256 | Alg::Rsa => "RS256",
+        Alg::Ed25519 => "EdDSA",
+    }
+}
+
+fn jwk_param(jwk: &Value, name: &str) -> Result<Vec<u8>, String> {
+    let p = jwk[name]
+        .as_str()
+        .ok_or_else(|| format!("jwk is missing '{}'", name))?;
+    decode_config(p, URL_SAFE_NO_PAD).map_err(|e| format!("jwk '{}': {}", name, e))
+}
+
+fn bignum(b: &[u8]) -> Result<BigNum, String> {
+    BigNum::from_slice(b).map_err(|e| format!("{}", e))
+}
+
+/// Keys per publishing authority.
+pub struct Keyset<K> {
+    keys: HashMap<PublisherAuthority, K>,
+}
+
+impl<K> Default for Keyset<K> {
+    fn default() -> Self {
+        Keyset {
+            keys: HashMap::new(),
+        }
+    }
+}
+
+impl<K> Keyset<K> {
+    /// Loads keys from `publisher=path` pairs like `hris=keys/hris.pem`.
+    pub fn load<'a>(
+        specs: impl IntoIterator<Item = &'a str>,
+        load: fn(&str) -> Result<K, String>,
+    ) -> Result<Self, String> {
+        let mut keys = HashMap::new();
+        for spec in specs {
+            let mut parts = spec.splitn(2, '=');
+            let (publisher, path) = match (parts.next(), parts.next()) {
+                (Some(publisher), Some(path)) => (parse_publisher(publisher)?, path),
+                _ => return Err(format!("expected <publisher>=<path>, got: {}", spec)),
+            };
+            keys.insert(publisher, load(path)?);
+        }
+        Ok(Keyset { keys })
+    }
+
+    pub fn get(&self, publisher: &PublisherAuthority) -> Option<&K> {
+        self.keys.get(publisher)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&PublisherAuthority, &K)> {
+        self.keys.iter()
+    }
+}
+
+/// The signed content of an attribute: the attribute without its signature.
+pub fn signing_payload(attribute: &Value) -> Result<Vec<u8>, String> {
+    let mut a = attribute.clone();
+    if let Some(o) = a.as_object_mut() {
+        o.remove("signature");
+    }
+    serde_json::to_vec(&a).map_err(|e| format!("{}", e))
+}
+
+/// Creates a compact JWS over `payload`.
+pub fn sign_compact(key: &Key, payload: &[u8]) -> Result<String, String> {
+    let header = json!({ "alg": jws_alg(&key.alg()), "typ": "JWS" });
+    let signing_input = format!(
+        "{}.{}",
+        encode_config(&header.to_string(), URL_SAFE_NO_PAD),
+        encode_config(payload, URL_SAFE_NO_PAD)
+    );
+    let signature = key.sign(signing_input.as_bytes())?;
+    Ok(format!(
+        "{}.{}",
+        signing_input,
+        encode_config(&signature, URL_SAFE_NO_PAD)
+    ))
+}
+
+/// Verifies a compact JWS and returns its payload.
+pub fn verify_compact(key: &PublicKey, jws: &str) -> Result<Vec<u8>, String> {
+    let parts: Vec<&str> = jws.split('.').collect();
+    if parts.len() != 3 {
+        return Err(String::from("malformed jws"));
+    }
+    let decode = |p| decode_config(p, URL_SAFE_NO_PAD).map_err(|e| format!("{}", e));
+    let header: Value = serde_json::from_slice(&decode(parts[0])?).map_err(|e| format!("{}", e))?;
+    if header["alg"].as_str() != Some(jws_alg(&key.alg())) {
+        return Err(format!("unexpected alg {}", header["alg"]));
+    }
+    let signing_input = format!("{}.{}", parts[0], parts[1]);
+    if key.verify(signing_input.as_bytes(), &decode(parts[2])?)? {
+        decode(parts[1])
+    } else {
+        Err(String::from("bad signature"))
+    }
+}
+
+/// Signs every attribute with the key of its publisher.
+pub fn sign_profile(p: Profile, keys: &Keyset<Key>) -> Result<Profile, String> {
+    let mut v = to_value(&p)?;
+    let mut error = None;
+    walk_mut(&mut v, &mut |path, a| {
+        if error.is_some() {
+            return;
+        }
+        if let Err(e) = sign_attribute(a, keys) {
+            error = Some(format!("{} ({})", e, path));
+        }
+    });
+    match error {
+        Some(e) => Err(e),
+        None => from_value(v),
+    }
+}
+
+fn sign_attribute(a: &mut Value, keys: &Keyset<Key>) -> Result<(), String> {
+    let publisher: PublisherAuthority =
+        serde_json::from_value(a["signature"]["publisher"]["name"].clone())
+            .map_err(|e| format!("{}", e))?;
+    let key = keys
+        .get(&publisher)
+        .ok_or_else(|| format!("no signing key for {}", publisher_name(&publisher)))?;
+    let jws = sign_compact(key, &signing_payload(a)?)?;
+    a["signature"]["publisher"]["alg"] = json!(key.alg());
+    a["signature"]["publisher"]["value"] = json!(jws);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hs256() {
+        let key = Key::from_jwk(&json!({ "kty": "oct", "k": "c2VjcmV0" })).unwrap();
+        let jws = sign_compact(&key, br#"{"value":"Hans"}"#).unwrap();
+        assert_eq!(
+            jws,
+            "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXUyJ9.eyJ2YWx1ZSI6IkhhbnMifQ.\
+             Af0TSsg32C2tIZnWuqJEoGYTIRH1HQjSiYg_RJks3Fw"
+        );
+        let payload = verify_compact(&key.public().unwrap(), &jws).unwrap();
+        assert_eq!(payload, br#"{"value":"Hans"}"#);
+    }
+
+    #[test]
+    fn test_ed25519_roundtrip() {
+        let pkey = PKey::generate_ed25519().unwrap();
+        let key = Key::from_pem(&pkey.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        let jws = sign_compact(&key, b"{}").unwrap();
+        let public = key.public().unwrap();
+        assert!(verify_compact(&public, &jws).is_ok());
+        let tampered = jws.replace(".e30.", ".e319.");
+        assert!(verify_compact(&public, &tampered).is_err());
+    }
+
+    #[test]
+    fn test_read_secret() {
+        let path = ::std::env::temp_dir().join(format!("v2conv-secret-{}", ::std::process::id()));
+        ::std::fs::write(&path, "s3cret\r\n").unwrap();
+        let path = path.to_string_lossy().into_owned();
+        assert_eq!(read_secret(Some(&path), "V2CONV_TEST_SECRET").unwrap(), b"s3cret");
+        ::std::fs::write(&path, "\n").unwrap();
+        assert!(read_secret(Some(&path), "V2CONV_TEST_SECRET").is_err());
+        ::std::fs::remove_file(&path).unwrap();
+
+        assert!(read_secret(None, "V2CONV_TEST_SECRET").is_err());
+        env::set_var("V2CONV_TEST_SECRET", "s3cret\n");
+        assert_eq!(read_secret(None, "V2CONV_TEST_SECRET").unwrap(), b"s3cret");
+        env::remove_var("V2CONV_TEST_SECRET");
+    }
+}
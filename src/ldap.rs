@@ -1,4 +1,3 @@
-use std::cmp::min;
 use std::path::PathBuf;
 
 use serde::de::Error;
@@ -7,14 +6,15 @@ use uuid::Uuid;
 
 use avatar::*;
 use schema::*;
-use username::generate_username;
+use username::{seed, Usernames};
 
 pub fn map_ldap(
     mut p2: Profile,
     mut ldap: Value,
     avatar_in: &Option<PathBuf>,
     avatar_out: &Option<PathBuf>,
-    entropy: &str,
+    avatars: &AvatarConfig,
+    usernames: &Usernames,
 ) -> Result<Profile, serde_json::Error> {
     let primary_email = ldap["primary_email"]["value"].take();
     let primary_email = primary_email
@@ -25,17 +25,11 @@ pub fn map_ldap(
         "{}",
         Uuid::new_v5(&Uuid::NAMESPACE_URL, primary_email.as_bytes())
     );
-    let username = ldap["usernames"]["values"]
+    let extracted = ldap["usernames"]["values"]
         .as_object()
-        .and_then(|o| {
-            o.values()
-                .filter_map(|v| {
-                    v.as_str().and_then(|s| match &s[0..min(s.len(), 3)] {
-                        "IRC" | "irc" => s.split(' ').last().map(String::from),
-                        _ => None,
-                    })
-                }).next()
-        }).unwrap_or_else(|| generate_username(&primary_email, entropy));
+        .map(|o| usernames.extractors.extract(o, &primary_email, usernames.report))
+        .unwrap_or_default();
+    let primary = usernames.extractors.primary(&extracted);
     if !ldap["first_name"]["value"].is_null() {
         p2.first_name.value = serde_json::from_value(ldap["first_name"]["value"].take())?;
     }
@@ -62,10 +56,11 @@ pub fn map_ldap(
         serde_json::from_value(ldap["identities"]["mozilla_posix_id"]["value"].take())?;
     p2.identities.mozilliansorg_id.value =
         serde_json::from_value(ldap["identities"]["mozilliansorg_id"]["value"].take())?;
-    p2.usernames.values = serde_json::from_value(ldap["usernames"]["values"].take())?;
+    p2.usernames.values = extracted.into_iter().collect();
     p2.user_id.value = serde_json::from_value(ldap["user_id"]["value"].take())?;
     p2.login_method.value = serde_json::from_value(ldap["login_method"]["value"].take())?;
     p2.primary_email.value = Some(primary_email);
+    let username = usernames.pick(primary, &seed(&p2));
     p2.access_information.ldap.values =
         serde_json::from_value(ldap["access_information"]["ldap"]["values"].take())?;
     p2.fun_title.value = serde_json::from_value(ldap["fun_title"]["value"].take())?;
@@ -76,7 +71,8 @@ pub fn map_ldap(
         &ldap["picture"],
         avatar_in,
         avatar_out,
-        &format!("{}.png", dinopark_id),
+        &dinopark_id,
+        avatars,
     ))?;
 
     p2.usernames
@@ -90,15 +86,16 @@ fn handle_picture(
     input_path: &Option<PathBuf>,
     output_path: &Option<PathBuf>,
     name: &str,
+    config: &AvatarConfig,
 ) -> Value {
     if let (Some(i), Some(o), Some(p)) = (input_path, output_path, v["value"].clone().as_str()) {
         let mut input = i.clone();
         let input_file_path = PathBuf::from(p);
         if let Some(input_file_name) = input_file_path.file_name() {
             input.push(input_file_name);
-            match convert_path(&input, &o, &name) {
-                Ok(()) => {
-                    return json!(name);
+            match convert_path(&input, o, name, config) {
+                Ok(picture) => {
+                    return json!(picture);
                 }
                 Err(e) => {
                     eprintln!("error handling picture: {}", e);
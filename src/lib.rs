@@ -1,9 +1,10 @@
 extern crate base64;
 extern crate chrono;
 extern crate chrono_tz;
-#[macro_use]
 extern crate clap;
+extern crate flate2;
 extern crate image;
+extern crate openssl;
 extern crate rand;
 extern crate regex;
 extern crate reqwest;
@@ -13,14 +14,33 @@ extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
 extern crate uuid;
+extern crate webp;
 
 pub mod app;
+mod anonymize;
+mod generate;
+mod attributes;
 mod username;
 mod tz;
+mod validate;
+mod verify;
+mod view;
 mod avatar;
+mod placeholder;
+mod download;
+#[cfg(test)]
+mod drift;
 mod hris;
+mod jws;
 mod ldap;
 mod loader;
+#[cfg(test)]
+mod mock;
 mod mozillians;
+mod publish;
+mod publisher;
+mod retry;
 mod schema;
+mod timestamp;
+mod split;
 mod writer;
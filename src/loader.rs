@@ -1,11 +1,14 @@
 use std::collections::HashMap;
+use std::fs;
 use std::fs::File;
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
 
+use flate2::read::GzDecoder;
 use serde_json::Value;
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct Data {
     pub hris: Value,
     pub ldap: Value,
@@ -21,6 +24,65 @@ pub fn load_json(path: impl Into<PathBuf>) -> Result<Value, String> {
     serde_json::from_str(&s).map_err(|e| format!("{}", e))
 }
 
+/// Loads profiles from a single file or a directory of `--split` output.
+/// Files may be JSON or newline-delimited JSON, optionally gzipped. If the
+/// directory has a `manifest.json`, only the files it lists are loaded, so
+/// leftovers of earlier runs are ignored.
+pub fn load_profiles(path: &str) -> Result<Vec<Value>, String> {
+    let p = Path::new(path);
+    let mut files = vec![];
+    let manifest = p.join("manifest.json");
+    if p.is_dir() && manifest.is_file() {
+        let manifest = load_json(&manifest)?;
+        for f in manifest["files"].as_array().into_iter().flatten() {
+            let name = f["file"]
+                .as_str()
+                .ok_or_else(|| format!("{}: file without a name in manifest.json", path))?;
+            files.push(p.join(name));
+        }
+    } else if p.is_dir() {
+        for entry in fs::read_dir(p).map_err(|e| format!("{}", e))? {
+            let file = entry.map_err(|e| format!("{}", e))?.path();
+            let name = file
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let profiles = [".json", ".ndjson", ".json.gz", ".ndjson.gz"]
+                .iter()
+                .any(|ext| name.ends_with(ext));
+            if profiles {
+                files.push(file);
+            }
+        }
+        files.sort();
+    } else {
+        files.push(p.to_path_buf());
+    }
+    let mut profiles = vec![];
+    for file in files {
+        let name = file.to_string_lossy().into_owned();
+        let mut s = String::new();
+        let f = File::open(&file).map_err(|e| format!("{}: {}", name, e))?;
+        if name.ends_with(".gz") {
+            GzDecoder::new(f).read_to_string(&mut s)
+        } else {
+            BufReader::new(f).read_to_string(&mut s)
+        }
+        .map_err(|e| format!("{}: {}", name, e))?;
+        if name.trim_end_matches(".gz").ends_with(".ndjson") {
+            for line in s.lines().filter(|l| !l.trim().is_empty()) {
+                profiles.push(serde_json::from_str(line).map_err(|e| format!("{}: {}", name, e))?);
+            }
+            continue;
+        }
+        match serde_json::from_str(&s).map_err(|e| format!("{}: {}", name, e))? {
+            Value::Array(a) => profiles.extend(a),
+            v => profiles.push(v),
+        }
+    }
+    Ok(profiles)
+}
+
 pub fn load_all(hris: &str, ldap: &str, mozillians: &str) -> Result<HashMap<String, Data>, String> {
     let mut h = HashMap::<String, Data>::new();
     let mut ldap_to_mail = HashMap::new();
@@ -39,7 +101,7 @@ fn load_hris(hris: &str, h: &mut HashMap<String, Data>) -> Result<(), String> {
     for e in hris_data["Report_Entry"]
         .as_array_mut()
         .ok_or_else(|| String::from("hirs data should be an array"))?
-        .into_iter()
+        .iter_mut()
         .map(|e| e.take())
     {
         let mail = e["PrimaryWorkEmail"].clone();
@@ -118,7 +180,7 @@ fn load_mozillians(
     for e in mozillians_data
         .as_array_mut()
         .ok_or_else(|| String::from("mozillians data should be an array"))?
-        .into_iter()
+        .iter_mut()
         .map(|e| e.take())
     {
         let user_id = e["user_id"].clone();
@@ -0,0 +1,78 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::thread;
+
+/// A request received by `serve`.
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// Header names in lower case with their values.
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Request {
+    /// The value of the header `name` (lower case), empty if there is none.
+    pub fn header(&self, name: &str) -> &str {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+            .unwrap_or_default()
+    }
+}
+
+/// A complete response with `headers` like `"ETag: \"v1\""` and `body`.
+pub fn response(status: &str, headers: &[&str], body: &str) -> String {
+    let mut resp = format!("HTTP/1.1 {}\r\n", status);
+    for h in headers {
+        resp += &format!("{}\r\n", h);
+    }
+    resp + &format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+}
+
+/// Answers every request on a local port with the response `respond` builds
+/// from it, one connection per request. Returns the base URL.
+pub fn serve<F>(mut respond: F) -> String
+where
+    F: FnMut(&Request) -> String + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let mut parts = line.split(' ');
+            let method = String::from(parts.next().unwrap_or_default());
+            let path = String::from(parts.next().unwrap_or_default());
+            let mut headers = vec![];
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                if let Some(i) = line.find(':') {
+                    let name = line[..i].trim().to_lowercase();
+                    headers.push((name, String::from(line[i + 1..].trim())));
+                }
+            }
+            let mut request = Request {
+                method,
+                path,
+                headers,
+                body: String::new(),
+            };
+            let length = request.header("content-length").parse().unwrap_or(0);
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            request.body = String::from_utf8(body).unwrap();
+            let resp = respond(&request);
+            stream.write_all(resp.as_bytes()).unwrap();
+        }
+    });
+    base
+}
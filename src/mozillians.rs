@@ -1,20 +1,22 @@
 use std::collections::BTreeMap;
 use std::path::PathBuf;
 
-use rand::distributions::Alphanumeric;
-use rand::{thread_rng, Rng};
-use reqwest;
 use serde::de::Error;
 use serde_json::Value;
 use uuid::Uuid;
 
 use avatar::*;
+use download::Pictures;
 use schema::*;
+use username::{generate_username, seed, Usernames};
 
 pub fn map_mozillians(
     mut p2: Profile,
     mut mozillians: Value,
     avatar_out: &Option<PathBuf>,
+    avatars: &AvatarConfig,
+    pictures: &Pictures,
+    usernames: &Usernames,
 ) -> Result<Profile, serde_json::Error> {
     if mozillians.is_null() {
         return Ok(p2);
@@ -30,7 +32,9 @@ pub fn map_mozillians(
         )
     );
     let m_username = mozillians["username"].as_str().map(String::from);
-    eprintln!("mozillian: {}", m_username.clone().unwrap_or_default());
+    if usernames.report {
+        eprintln!("mozillian: {}", m_username.clone().unwrap_or_default());
+    }
     if p2.first_name.value.is_none() {
         p2.first_name.value = serde_json::from_value(mozillians["first_name"].take())?;
     }
@@ -65,13 +69,13 @@ pub fn map_mozillians(
     let m_tags = mozillians["tags"].take();
     let m_tags = m_tags
         .as_array()
-        .map(|a| a.into_iter())
-        .unwrap_or_else(|| (&[]).into_iter());
+        .map(|a| a.iter())
+        .unwrap_or_else(|| [].iter());
     let m_skills = mozillians["skills"].take();
     let m_skills = m_skills
         .as_array()
-        .map(|a| a.into_iter())
-        .unwrap_or_else(|| (&[]).into_iter());
+        .map(|a| a.iter())
+        .unwrap_or_else(|| [].iter());
     let tags: BTreeMap<String, Value> = m_tags
         .chain(m_skills)
         .filter_map(|x| x.as_str().map(|s| (String::from(s), Value::default())))
@@ -81,8 +85,8 @@ pub fn map_mozillians(
     let m_languages = mozillians["preferred_language"].take();
     let languages: BTreeMap<String, Value> = m_languages
         .as_array()
-        .map(|a| a.into_iter())
-        .unwrap_or_else(|| (&[]).into_iter())
+        .map(|a| a.iter())
+        .unwrap_or_else(|| [].iter())
         .filter_map(|x| x.as_str().map(|s| (String::from(s), Value::default())))
         .collect();
     p2.languages.values = languages;
@@ -105,7 +109,9 @@ pub fn map_mozillians(
         p2.picture.value = serde_json::from_value(handle_picture(
             &mozillians["picture"],
             avatar_out,
-            &format!("{}.png", dinopark_id),
+            &dinopark_id,
+            avatars,
+            pictures,
         ))?;
     }
 
@@ -113,12 +119,16 @@ pub fn map_mozillians(
         p2.primary_email.value = serde_json::from_value(mozillians["idps"][0]["email"].take())?;
     }
 
-    if let Some(username) = m_username {
+    let user = p2.user_id.value.clone().unwrap_or_default();
+    if let Some(username) = usernames.valid(m_username, &user) {
         p2.usernames
             .values
             .insert(String::from("mozilliansorg"), username.into());
-    } else if p2.usernames.values.get("mozilliansorg").is_none() {
-        let username: String = thread_rng().sample_iter(&Alphanumeric).take(30).collect();
+    } else if !p2.usernames.values.contains_key("mozilliansorg") {
+        let id = Some(seed(&p2))
+            .filter(|id| !id.is_empty())
+            .unwrap_or(dinopark_id);
+        let username = generate_username(&id, &usernames.key);
         p2.usernames
             .values
             .insert(String::from("mozilliansorg"), username.into());
@@ -126,19 +136,25 @@ pub fn map_mozillians(
     Ok(p2)
 }
 
-fn handle_picture(v: &Value, output_path: &Option<PathBuf>, name: &str) -> Value {
-    if let (Some(o), Some(u)) = (output_path, v.clone().as_str()) {
-        if let Ok(mut resp) = reqwest::get(u) {
-            let mut buf: Vec<u8> = vec![];
-            if resp.copy_to(&mut buf).is_ok() {
-                match convert_buf(&buf, &o, &name) {
-                    Ok(()) => {
-                        return json!(name);
-                    }
-                    Err(e) => {
-                        eprintln!("error handling picture: {}", e);
-                    }
-                };
+fn handle_picture(
+    v: &Value,
+    output_path: &Option<PathBuf>,
+    name: &str,
+    config: &AvatarConfig,
+    pictures: &Pictures,
+) -> Value {
+    if let (Some(o), Some(u)) = (output_path, v.as_str()) {
+        match pictures.get(u) {
+            Ok(buf) => match convert_buf(&buf, o, name, config) {
+                Ok(picture) => {
+                    return json!(picture);
+                }
+                Err(e) => {
+                    eprintln!("error handling picture ({}): {}", u, e);
+                }
+            },
+            Err(e) => {
+                eprintln!("error downloading picture: {}", e);
             }
         }
     };
@@ -0,0 +1,206 @@
+use image::{Rgb, RgbImage};
+use openssl::sha::sha256;
+
+/// How placeholders for profiles without a picture look.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Placeholder {
+    /// Up to two initials of the name, or an identicon if the name has no
+    /// letters or digits we can draw.
+    Initials,
+    /// A symmetric 5x5 pattern.
+    Identicon,
+}
+
+const GLYPHS: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+/// 5x7 pixel glyphs of `GLYPHS`, one row per byte.
+const FONT: [[u8; 7]; 36] = [
+    [0x0e, 0x11, 0x11, 0x1f, 0x11, 0x11, 0x11],
+    [0x1e, 0x11, 0x11, 0x1e, 0x11, 0x11, 0x1e],
+    [0x0e, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0e],
+    [0x1e, 0x11, 0x11, 0x11, 0x11, 0x11, 0x1e],
+    [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x1f],
+    [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x10],
+    [0x0e, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0f],
+    [0x11, 0x11, 0x11, 0x1f, 0x11, 0x11, 0x11],
+    [0x0e, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0e],
+    [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0c],
+    [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+    [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1f],
+    [0x11, 0x1b, 0x15, 0x15, 0x11, 0x11, 0x11],
+    [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+    [0x0e, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0e],
+    [0x1e, 0x11, 0x11, 0x1e, 0x10, 0x10, 0x10],
+    [0x0e, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0d],
+    [0x1e, 0x11, 0x11, 0x1e, 0x14, 0x12, 0x11],
+    [0x0f, 0x10, 0x10, 0x0e, 0x01, 0x01, 0x1e],
+    [0x1f, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+    [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0e],
+    [0x11, 0x11, 0x11, 0x11, 0x11, 0x0a, 0x04],
+    [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0a],
+    [0x11, 0x11, 0x0a, 0x04, 0x0a, 0x11, 0x11],
+    [0x11, 0x11, 0x11, 0x0a, 0x04, 0x04, 0x04],
+    [0x1f, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1f],
+    [0x0e, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0e],
+    [0x04, 0x0c, 0x04, 0x04, 0x04, 0x04, 0x0e],
+    [0x0e, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1f],
+    [0x1f, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0e],
+    [0x02, 0x06, 0x0a, 0x12, 0x1f, 0x02, 0x02],
+    [0x1f, 0x10, 0x1e, 0x01, 0x01, 0x11, 0x0e],
+    [0x06, 0x08, 0x10, 0x1e, 0x11, 0x11, 0x0e],
+    [0x1f, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+    [0x0e, 0x11, 0x11, 0x0e, 0x11, 0x11, 0x0e],
+    [0x0e, 0x11, 0x11, 0x0f, 0x01, 0x02, 0x0c],
+];
+const WHITE: Rgb<u8> = Rgb {
+    data: [255, 255, 255],
+};
+const LIGHT: Rgb<u8> = Rgb {
+    data: [240, 240, 240],
+};
+
+/// The first letter or digit of up to two words of `name`, if all of them
+/// can be drawn.
+pub fn initials(name: &str) -> Option<String> {
+    let initials: String = name
+        .split_whitespace()
+        .filter_map(|w| w.chars().find(|c| c.is_alphanumeric()))
+        .flat_map(char::to_uppercase)
+        .take(2)
+        .collect();
+    if initials.is_empty() || initials.chars().any(|c| !GLYPHS.contains(c)) {
+        None
+    } else {
+        Some(initials)
+    }
+}
+
+/// A `side` pixels wide placeholder for the profile `id` named `name`. The
+/// same arguments always give the same image.
+pub fn render(style: Placeholder, id: &str, name: &str, side: u32) -> RgbImage {
+    let hash = sha256(id.as_bytes());
+    let color = hsl(
+        f64::from(u16::from(hash[0]) << 8 | u16::from(hash[1])) % 360.0,
+        0.5,
+        0.45,
+    );
+    match (style, initials(name)) {
+        (Placeholder::Initials, Some(initials)) => draw_initials(&initials, color, side),
+        _ => identicon(&hash, color, side),
+    }
+}
+
+fn draw_initials(initials: &str, color: Rgb<u8>, side: u32) -> RgbImage {
+    let mut img = RgbImage::from_pixel(side, side, color);
+    let count = initials.chars().count() as u32;
+    // text is about two fifths of the height
+    let scale = (side * 2 / 35).max(1);
+    let width = (count * 6 - 1) * scale;
+    let (left, top) = (
+        side.saturating_sub(width) / 2,
+        side.saturating_sub(7 * scale) / 2,
+    );
+    for (i, c) in initials.chars().enumerate() {
+        let glyph = match GLYPHS.find(c) {
+            Some(g) => FONT[g],
+            None => continue,
+        };
+        let x0 = left + i as u32 * 6 * scale;
+        for (row, bits) in glyph.iter().enumerate() {
+            for col in 0..5 {
+                if bits & (0x10 >> col) != 0 {
+                    fill(
+                        &mut img,
+                        x0 + col * scale,
+                        top + row as u32 * scale,
+                        scale,
+                        WHITE,
+                    );
+                }
+            }
+        }
+    }
+    img
+}
+
+fn identicon(hash: &[u8], color: Rgb<u8>, side: u32) -> RgbImage {
+    let mut img = RgbImage::from_pixel(side, side, LIGHT);
+    let cell = (side / 6).max(1);
+    let margin = side.saturating_sub(5 * cell) / 2;
+    for row in 0..5 {
+        for col in 0..3 {
+            let bit = row * 3 + col;
+            if hash[2 + bit / 8] & (1 << (bit % 8)) == 0 {
+                continue;
+            }
+            for x in &[col, 4 - col] {
+                fill(
+                    &mut img,
+                    margin + *x as u32 * cell,
+                    margin + row as u32 * cell,
+                    cell,
+                    color,
+                );
+            }
+        }
+    }
+    img
+}
+
+fn fill(img: &mut RgbImage, x0: u32, y0: u32, size: u32, color: Rgb<u8>) {
+    for y in y0..(y0 + size).min(img.height()) {
+        for x in x0..(x0 + size).min(img.width()) {
+            img.put_pixel(x, y, color);
+        }
+    }
+}
+
+fn hsl(h: f64, s: f64, l: f64) -> Rgb<u8> {
+    let c = (1.0 - (2.0 * l - 1.0).abs()) * s;
+    let x = c * (1.0 - ((h / 60.0) % 2.0 - 1.0).abs());
+    let (r, g, b) = match (h / 60.0) as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = l - c / 2.0;
+    let byte = |v: f64| ((v + m) * 255.0).round() as u8;
+    Rgb([byte(r), byte(g), byte(b)])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        assert_eq!(initials("Jane van Doe"), Some(String::from("JV")));
+        assert_eq!(initials("Élodie"), None);
+        assert_eq!(initials("  "), None);
+
+        let a = render(Placeholder::Initials, "id-a", "Jane Doe", 70);
+        let pixels = |id, name| render(Placeholder::Initials, id, name, 70).into_raw();
+        assert_eq!(a.clone().into_raw(), pixels("id-a", "Jane Doe"));
+        assert_ne!(a.clone().into_raw(), pixels("id-b", "Jane Doe"));
+        assert_eq!(a.dimensions(), (70, 70));
+        // the background is the profile colour and the initials are white
+        assert_ne!(a.get_pixel(0, 0), &WHITE);
+        assert!(a.pixels().any(|p| *p == WHITE));
+
+        let identicon = render(Placeholder::Identicon, "id-a", "Jane Doe", 60);
+        assert_eq!(identicon.get_pixel(0, 0), &LIGHT);
+        for y in 0..60 {
+            for x in 0..60 {
+                assert_eq!(identicon.get_pixel(x, y), identicon.get_pixel(59 - x, y));
+            }
+        }
+        // nothing to draw for the name, so an identicon
+        assert_eq!(
+            render(Placeholder::Initials, "id-a", "李", 60).into_raw(),
+            identicon.into_raw()
+        );
+    }
+}
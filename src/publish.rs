@@ -0,0 +1,376 @@
+use std::slice;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, Response, StatusCode};
+use serde_json::Value;
+
+use attributes::user;
+use jws::read_secret;
+use retry::{delay, retry_after};
+use writer::sha256_hex;
+
+pub const CLIENT_SECRET_ENV: &str = "V2CONV_CLIENT_SECRET";
+
+/// OAuth client credentials for the change API.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientCredentials {
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub audience: Option<String>,
+}
+
+impl ClientCredentials {
+    /// Reads the client secret from `path` or `$V2CONV_CLIENT_SECRET`.
+    pub fn load_secret(path: Option<&str>) -> Result<String, String> {
+        String::from_utf8(read_secret(path, CLIENT_SECRET_ENV)?)
+            .map_err(|e| format!("client secret: {}", e))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PublishConfig {
+    /// The change endpoint every batch is POSTed to as a JSON array.
+    pub url: String,
+    pub batch_size: usize,
+    /// Attempts after the first one for connection errors, 401 (with a new
+    /// token), 429 and 5xx responses.
+    pub retries: u32,
+    /// Wait before the first retry, doubled for every further one, unless
+    /// the response has a `Retry-After`. No wait is longer than `timeout`.
+    pub backoff: Duration,
+    pub timeout: Duration,
+    /// Least time between two requests to the change endpoint.
+    pub interval: Option<Duration>,
+    pub credentials: Option<ClientCredentials>,
+}
+
+impl Default for PublishConfig {
+    fn default() -> Self {
+        PublishConfig {
+            url: String::new(),
+            batch_size: 10,
+            retries: 3,
+            backoff: Duration::from_secs(1),
+            timeout: Duration::from_secs(30),
+            interval: None,
+            credentials: None,
+        }
+    }
+}
+
+/// What happened to the profile of a user, a line of the result log.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Outcome {
+    pub user_id: String,
+    pub batch: usize,
+    pub published: bool,
+    pub status: Option<u16>,
+    pub error: Option<String>,
+}
+
+enum Failure {
+    Retry {
+        status: Option<StatusCode>,
+        error: String,
+        wait: Option<Duration>,
+    },
+    Fatal {
+        status: Option<StatusCode>,
+        error: String,
+    },
+}
+
+pub struct Publisher {
+    client: Client,
+    config: PublishConfig,
+    token: Option<(String, Instant)>,
+    last: Option<Instant>,
+}
+
+impl Publisher {
+    pub fn new(config: PublishConfig) -> Result<Self, String> {
+        let client = Client::builder()
+            .timeout(config.timeout)
+            .build()
+            .map_err(|e| format!("{}", e))?;
+        Ok(Publisher {
+            client,
+            config,
+            token: None,
+            last: None,
+        })
+    }
+
+    /// POSTs `profiles` in batches and returns the outcome for every
+    /// profile. A failed batch does not stop the following ones. When the
+    /// endpoint rejects a batch, its profiles are sent one by one so only
+    /// the offending ones are reported as failed.
+    pub fn publish(&mut self, profiles: &[Value]) -> Vec<Outcome> {
+        let mut outcomes = vec![];
+        for (i, batch) in profiles.chunks(self.config.batch_size.max(1)).enumerate() {
+            let result = self.post(batch);
+            match result {
+                Err((Some(status), _)) if batch.len() > 1 && rejected(status) => {
+                    for p in batch {
+                        let result = self.post(slice::from_ref(p));
+                        outcomes.push(outcome(p, i, result));
+                    }
+                }
+                _ => outcomes.extend(batch.iter().map(|p| outcome(p, i, result.clone()))),
+            }
+        }
+        outcomes
+    }
+
+    /// Sends a batch until it is accepted or the retries are used up. Every
+    /// attempt carries the SHA-256 of the batch as `Idempotency-Key`, so the
+    /// endpoint can tell retries from new changes.
+    fn post(&mut self, batch: &[Value]) -> Result<StatusCode, (Option<StatusCode>, String)> {
+        let body = serde_json::to_vec(batch).map_err(|e| (None, format!("{}", e)))?;
+        let key = sha256_hex(&body);
+        let mut attempt = 0;
+        loop {
+            match self.attempt(&body, &key) {
+                Ok(status) => return Ok(status),
+                Err(Failure::Retry { error, wait, .. }) if attempt < self.config.retries => {
+                    let c = &self.config;
+                    thread::sleep(delay(wait, c.backoff, attempt, c.timeout));
+                    attempt += 1;
+                    eprintln!("retrying batch {} ({})", &key[..8], error);
+                }
+                Err(Failure::Retry { status, error, .. })
+                | Err(Failure::Fatal { status, error }) => return Err((status, error)),
+            }
+        }
+    }
+
+    fn attempt(&mut self, body: &[u8], key: &str) -> Result<StatusCode, Failure> {
+        let token = self.token()?;
+        if let (Some(interval), Some(last)) = (self.config.interval, self.last) {
+            if let Some(wait) = interval.checked_sub(last.elapsed()) {
+                thread::sleep(wait);
+            }
+        }
+        self.last = Some(Instant::now());
+        let mut request = self
+            .client
+            .post(&self.config.url)
+            .header(CONTENT_TYPE, "application/json")
+            .header("Idempotency-Key", key)
+            .body(body.to_vec());
+        if let Some(token) = &token {
+            request = request.bearer_auth(token);
+        }
+        let mut resp = request.send().map_err(|e| Failure::Retry {
+            status: None,
+            error: format!("{}", e),
+            wait: None,
+        })?;
+        let status = resp.status();
+        if status == StatusCode::UNAUTHORIZED && token.is_some() {
+            // the token may have been revoked, get a new one
+            self.token = None;
+            return Err(Failure::Retry {
+                status: Some(status),
+                error: format!("status {}", status),
+                wait: Some(Duration::from_secs(0)),
+            });
+        }
+        check(&mut resp)?;
+        Ok(status)
+    }
+
+    /// A bearer token from the client credentials grant, reused until a
+    /// minute before it expires.
+    fn token(&mut self) -> Result<Option<String>, Failure> {
+        let credentials = match &self.config.credentials {
+            Some(credentials) => credentials,
+            None => return Ok(None),
+        };
+        if let Some((token, expires)) = &self.token {
+            if Instant::now() < *expires {
+                return Ok(Some(token.clone()));
+            }
+        }
+        let mut form = vec![
+            ("grant_type", "client_credentials"),
+            ("client_id", credentials.client_id.as_str()),
+            ("client_secret", credentials.client_secret.as_str()),
+        ];
+        if let Some(audience) = &credentials.audience {
+            form.push(("audience", audience.as_str()));
+        }
+        let mut resp = self
+            .client
+            .post(&credentials.token_url)
+            .form(&form)
+            .send()
+            .map_err(|e| Failure::Retry {
+                status: None,
+                error: format!("token request: {}", e),
+                wait: None,
+            })?;
+        check(&mut resp)?;
+        let v: Value = resp.json().map_err(|e| Failure::Fatal {
+            status: None,
+            error: format!("token response: {}", e),
+        })?;
+        let token = v["access_token"]
+            .as_str()
+            .map(String::from)
+            .ok_or_else(|| Failure::Fatal {
+                status: None,
+                error: String::from("token response without access_token"),
+            })?;
+        let lifetime = v["expires_in"].as_u64().unwrap_or(3600).saturating_sub(60);
+        self.token = Some((
+            token.clone(),
+            Instant::now() + Duration::from_secs(lifetime),
+        ));
+        Ok(Some(token))
+    }
+}
+
+fn outcome(
+    p: &Value,
+    batch: usize,
+    result: Result<StatusCode, (Option<StatusCode>, String)>,
+) -> Outcome {
+    let (status, error) = match result {
+        Ok(status) => (Some(status), None),
+        Err((status, error)) => (status, Some(error)),
+    };
+    Outcome {
+        user_id: String::from(user(p)),
+        batch,
+        published: error.is_none(),
+        status: status.map(|s| s.as_u16()),
+        error,
+    }
+}
+
+/// Whether the endpoint refused the content of a request, as opposed to the
+/// credentials or the request rate.
+fn rejected(status: StatusCode) -> bool {
+    status.is_client_error()
+        && status != StatusCode::UNAUTHORIZED
+        && status != StatusCode::FORBIDDEN
+        && status != StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Turns 429 and 5xx responses into retries and other unsuccessful ones into
+/// failures.
+fn check(resp: &mut Response) -> Result<(), Failure> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let error = match resp.text() {
+        Ok(ref text) if !text.is_empty() => {
+            format!(
+                "status {}: {}",
+                status,
+                text.chars().take(200).collect::<String>()
+            )
+        }
+        _ => format!("status {}", status),
+    };
+    if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+        return Err(Failure::Retry {
+            status: Some(status),
+            error,
+            wait: retry_after(resp.headers()),
+        });
+    }
+    Err(Failure::Fatal {
+        status: Some(status),
+        error,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mock::{response, serve};
+    use std::sync::{Arc, Mutex};
+
+    /// Serves `/token` and `/users`. The first batch is answered with a 429
+    /// and batches with the user `bad` with a 400. Returns the base URL and
+    /// the requests as `<path> <idempotency key>`.
+    fn serve_cis() -> (String, Arc<Mutex<Vec<String>>>) {
+        let requests = Arc::new(Mutex::new(vec![]));
+        let log = requests.clone();
+        let base = serve(move |r| {
+            assert_eq!(r.method, "POST");
+            let mut log = log.lock().unwrap();
+            log.push(format!("{} {}", r.path, r.header("idempotency-key")));
+            if r.path == "/token" {
+                assert!(r.body.contains("grant_type=client_credentials"));
+                assert!(r.body.contains("client_secret=s3cret"));
+                let token = r#"{"access_token":"t1","expires_in":3600}"#;
+                response("200 OK", &[], token)
+            } else if r.header("authorization") != "Bearer t1" {
+                response("401 Unauthorized", &[], "")
+            } else if log.len() == 2 {
+                response("429 Too Many Requests", &["Retry-After: 0"], "")
+            } else if r.body.contains("\"bad\"") {
+                response("400 Bad Request", &[], "invalid")
+            } else {
+                response("200 OK", &[], "{}")
+            }
+        });
+        (base, requests)
+    }
+
+    #[test]
+    fn test_publish() {
+        let (base, requests) = serve_cis();
+        let mut publisher = Publisher::new(PublishConfig {
+            url: format!("{}/users", base),
+            batch_size: 2,
+            backoff: Duration::from_millis(1),
+            credentials: Some(ClientCredentials {
+                token_url: format!("{}/token", base),
+                client_id: String::from("v2conv"),
+                client_secret: String::from("s3cret"),
+                audience: None,
+            }),
+            ..PublishConfig::default()
+        })
+        .unwrap();
+        let profiles: Vec<Value> = ["a", "b", "bad", "c"]
+            .iter()
+            .map(|u| json!({ "user_id": { "value": u } }))
+            .collect();
+        let outcomes = publisher.publish(&profiles);
+        let published: Vec<bool> = outcomes.iter().map(|o| o.published).collect();
+        // only the rejected profile of the second batch fails
+        assert_eq!(published, vec![true, true, false, true]);
+        assert_eq!(outcomes[1].status, Some(200));
+        assert_eq!(outcomes[2].user_id, "bad");
+        assert_eq!(outcomes[2].batch, 1);
+        assert_eq!(outcomes[2].status, Some(400));
+        assert!(outcomes[2].error.as_ref().unwrap().contains("invalid"));
+        assert_eq!(outcomes[3].user_id, "c");
+        assert_eq!(outcomes[3].batch, 1);
+        assert_eq!(outcomes[3].status, Some(200));
+        assert_eq!(outcomes[3].error, None);
+
+        let requests = requests.lock().unwrap();
+        let paths: Vec<&str> = requests
+            .iter()
+            .map(|r| &r[..r.find(' ').unwrap()])
+            .collect();
+        // one token for all batches
+        assert_eq!(paths, vec!["/token", "/users", "/users", "/users", "/users", "/users"]);
+        // the retry of the first batch is recognizable as such
+        assert_eq!(requests[1], requests[2]);
+        assert_ne!(requests[2], requests[3]);
+        // the rejected batch is sent again profile by profile
+        assert_ne!(requests[3], requests[4]);
+        assert_ne!(requests[4], requests[5]);
+    }
+}
@@ -0,0 +1,75 @@
+use serde_json::Value;
+
+use schema::PublisherAuthority;
+
+/// Publishers whose attributes are marked as verified.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrustPolicy {
+    trusted: Vec<PublisherAuthority>,
+}
+
+impl Default for TrustPolicy {
+    fn default() -> Self {
+        TrustPolicy {
+            trusted: vec![PublisherAuthority::Hris, PublisherAuthority::Ldap],
+        }
+    }
+}
+
+impl TrustPolicy {
+    /// Parses a comma separated list of publisher names like `hris,ldap`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let trusted = s
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(parse_publisher)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(TrustPolicy { trusted })
+    }
+
+    pub fn verifies(&self, publisher: &PublisherAuthority) -> bool {
+        self.trusted.contains(publisher)
+    }
+}
+
+pub fn parse_publisher(s: &str) -> Result<PublisherAuthority, String> {
+    serde_json::from_value(json!(s)).map_err(|_| format!("unknown publisher: {}", s))
+}
+
+pub fn publisher_name(publisher: &PublisherAuthority) -> String {
+    json!(publisher)
+        .as_str()
+        .map(String::from)
+        .unwrap_or_default()
+}
+
+/// Records `publisher` as the publisher of the attributes at `paths` and
+/// marks them verified according to `policy`.
+pub fn attribute(
+    v: &mut Value,
+    paths: &[String],
+    publisher: &PublisherAuthority,
+    policy: &TrustPolicy,
+) {
+    for path in paths {
+        if let Some(a) = v.pointer_mut(path) {
+            a["signature"]["publisher"]["name"] = json!(publisher);
+            a["metadata"]["verified"] = json!(policy.verifies(publisher));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_policy() {
+        let policy = TrustPolicy::parse("hris, mozilliansorg").unwrap();
+        assert!(policy.verifies(&PublisherAuthority::Hris));
+        assert!(policy.verifies(&PublisherAuthority::Mozilliansorg));
+        assert!(!policy.verifies(&PublisherAuthority::Ldap));
+        assert!(TrustPolicy::parse("hris,workday").is_err());
+    }
+}
@@ -0,0 +1,64 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+
+/// The wait a 429 or 503 response asks for with `Retry-After`, given either
+/// in seconds or as an HTTP-date. Dates in the past mean no wait.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let v = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = v.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = DateTime::parse_from_rfc2822(v).ok()?;
+    Some(
+        date.signed_duration_since(Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+/// Wait before retry number `attempt`, counting from 0: `base` doubled for
+/// every earlier retry.
+pub fn backoff(base: Duration, attempt: u32) -> Duration {
+    base * 2u32.saturating_pow(attempt)
+}
+
+/// Wait before retry number `attempt`: what the server asked for or else the
+/// backoff, but never longer than `max`, as servers can ask for any wait.
+pub fn delay(asked: Option<Duration>, base: Duration, attempt: u32, max: Duration) -> Duration {
+    asked.unwrap_or_else(|| backoff(base, attempt)).min(max)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn test_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("120"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(120)));
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("soon"));
+        assert_eq!(retry_after(&headers), None);
+        let date = "Wed, 21 Oct 2015 07:28:00 GMT";
+        headers.insert(RETRY_AFTER, HeaderValue::from_static(date));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(0)));
+        let later = Utc::now() + ::chrono::Duration::minutes(2);
+        let date = later.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(&date).unwrap());
+        let wait = retry_after(&headers).unwrap();
+        assert!(wait > Duration::from_secs(110) && wait <= Duration::from_secs(120));
+
+        let base = Duration::from_millis(500);
+        assert_eq!(backoff(base, 0), base);
+        assert_eq!(backoff(base, 3), Duration::from_secs(4));
+        let max = Duration::from_secs(30);
+        assert_eq!(delay(None, base, 3, max), Duration::from_secs(4));
+        assert_eq!(delay(None, base, 10, max), max);
+        assert_eq!(delay(Some(Duration::from_secs(2)), base, 3, max), Duration::from_secs(2));
+        assert_eq!(delay(Some(Duration::from_secs(99_999_999)), base, 0, max), max);
+    }
+}
@@ -35,13 +35,14 @@ pub enum Alg {
     #[serde(rename = "ED25519")]
     Ed25519,
 }
-#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
+#[derive(Clone, PartialEq, Debug, Default, Deserialize, Serialize)]
 pub enum Classification {
     #[serde(rename = "MOZILLA CONFIDENTIAL")]
     MozillaConfidential,
     #[serde(rename = "WORKGROUP CONFIDENTIAL: STAFF ONLY")]
     WorkgroupConfidentialStaffOnly,
     #[serde(rename = "WORKGROUP CONFIDENTIAL")]
+    #[default]
     WorkgroupConfidential,
     #[serde(rename = "PUBLIC")]
     Public,
@@ -49,13 +50,8 @@ pub enum Classification {
     IndividualConfidential,
 }
 
-impl Default for Classification {
-    fn default() -> Self {
-        Classification::WorkgroupConfidential
-    }
-}
-
-#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
+/// Audiences ordered from the widest to the narrowest.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Deserialize, Serialize)]
 pub enum Display {
     #[serde(rename = "public")]
     Public,
@@ -98,7 +94,7 @@ impl Metadata {
     }
 }
 
-#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Deserialize, Serialize)]
 pub enum PublisherAuthority {
     #[serde(rename = "ldap")]
     Ldap,
@@ -131,7 +127,7 @@ impl Default for Signature {
             additional: vec![],
             publisher: Publisher {
                 alg: Alg::Hs256,
-                name: PublisherAuthority::Mozilliansorg,
+                name: PublisherAuthority::Cis,
                 typ: Typ::Jws,
                 value: String::default(),
             },
@@ -317,11 +313,29 @@ pub struct StaffInformationValuesArray {
 impl Default for StaffInformationValuesArray {
     fn default() -> Self {
         StaffInformationValuesArray {
-            manager: StandardAttributeBoolean::default(),
-            director: StandardAttributeBoolean::default(),
-            staff: StandardAttributeBoolean::default(),
-            title: StandardAttributeString::default(),
-            team: StandardAttributeString::default(),
+            manager: StandardAttributeBoolean::with(
+                false,
+                Some(Display::Staff),
+                Classification::MozillaConfidential,
+            ),
+            director: StandardAttributeBoolean::with(
+                false,
+                Some(Display::Staff),
+                Classification::MozillaConfidential,
+            ),
+            staff: StandardAttributeBoolean::with(
+                false,
+                Some(Display::Staff),
+                Classification::MozillaConfidential,
+            ),
+            title: StandardAttributeString::with(
+                Some(Display::Staff),
+                Classification::MozillaConfidential,
+            ),
+            team: StandardAttributeString::with(
+                Some(Display::Staff),
+                Classification::MozillaConfidential,
+            ),
             cost_center: StandardAttributeString::with(
                 Some(Display::Staff),
                 Classification::WorkgroupConfidentialStaffOnly,
@@ -330,8 +344,14 @@ impl Default for StaffInformationValuesArray {
                 Some(Display::Staff),
                 Classification::WorkgroupConfidentialStaffOnly,
             ),
-            wpr_desk_number: StandardAttributeString::default(),
-            office_location: StandardAttributeString::default(),
+            wpr_desk_number: StandardAttributeString::with(
+                Some(Display::Staff),
+                Classification::MozillaConfidential,
+            ),
+            office_location: StandardAttributeString::with(
+                Some(Display::Staff),
+                Classification::MozillaConfidential,
+            ),
         }
     }
 }
@@ -0,0 +1,314 @@
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde_json::Value;
+
+use attributes::user;
+use writer::{sha256_hex, write};
+
+/// How split output files are named.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileNaming {
+    /// `0.json`, `1.json`, …
+    Index,
+    /// `<index>-<first user_id>--<last user_id>.json`, the user ids cut to
+    /// `MAX_ID_LEN` characters. The index keeps files apart whose user ids
+    /// only differ in characters not allowed in file names.
+    Range,
+    /// The SHA-256 of the file.
+    Hash,
+}
+
+/// Most characters of a user id in a `FileNaming::Range` file name.
+pub const MAX_ID_LEN: usize = 64;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SplitOptions {
+    /// Most profiles per file.
+    pub profiles: Option<usize>,
+    /// Most bytes per file before compression. A file holds at least one
+    /// profile, however large.
+    pub bytes: Option<usize>,
+    pub naming: FileNaming,
+    pub gzip: bool,
+    /// One compact profile per line instead of a pretty printed array.
+    pub ndjson: bool,
+}
+
+impl Default for SplitOptions {
+    fn default() -> Self {
+        SplitOptions {
+            profiles: None,
+            bytes: None,
+            naming: FileNaming::Index,
+            gzip: false,
+            ndjson: false,
+        }
+    }
+}
+
+struct Part {
+    path: PathBuf,
+    out: Box<dyn Write>,
+    user_ids: Vec<String>,
+    bytes: usize,
+}
+
+/// Writes profiles into a directory as they come, starting a new file
+/// whenever the limits of `SplitOptions` are hit. `finish` adds a
+/// `manifest.json` listing every file with its user IDs, count, byte length
+/// and SHA-256.
+pub struct SplitWriter {
+    dir: PathBuf,
+    options: SplitOptions,
+    part: Option<Part>,
+    files: Vec<Value>,
+}
+
+impl SplitWriter {
+    pub fn new(dir: &str, options: SplitOptions) -> Result<Self, String> {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(&dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        Ok(SplitWriter {
+            dir,
+            options,
+            part: None,
+            files: vec![],
+        })
+    }
+
+    pub fn write(&mut self, p: &Value) -> Result<(), String> {
+        let record = if self.options.ndjson {
+            serde_json::to_string(p).map(|s| s + "\n")
+        } else {
+            // as the element of a pretty printed array
+            serde_json::to_string_pretty(&[p]).map(|s| String::from(&s[2..s.len() - 2]))
+        }
+        .map_err(|e| format!("{}", e))?;
+        let full = match &self.part {
+            Some(part) => {
+                let count = part.user_ids.len();
+                self.options.profiles.is_some_and(|max| count >= max)
+                    || self.options.bytes.is_some_and(|max| {
+                        part.bytes + record.len() + self.overhead(count + 1) > max
+                    })
+            }
+            None => false,
+        };
+        if full {
+            self.close()?;
+        }
+        if self.part.is_none() {
+            self.open()?;
+        }
+        let ndjson = self.options.ndjson;
+        if let Some(part) = &mut self.part {
+            let separator = match (ndjson, part.user_ids.len()) {
+                (true, _) => "",
+                (false, 0) => "[\n",
+                (false, _) => ",\n",
+            };
+            part.out
+                .write_all(separator.as_bytes())
+                .and_then(|_| part.out.write_all(record.as_bytes()))
+                .map_err(|e| format!("{}", e))?;
+            part.bytes += record.len();
+            part.user_ids.push(String::from(user(p)));
+        }
+        Ok(())
+    }
+
+    /// Closes the last file and writes `manifest.json`.
+    pub fn finish(mut self) -> Result<Value, String> {
+        if self.part.is_none() && self.files.is_empty() {
+            self.open()?;
+        }
+        self.close()?;
+        let manifest = json!({
+            "count": self.files.iter().filter_map(|f| f["count"].as_u64()).sum::<u64>(),
+            "files": self.files,
+        });
+        let s = serde_json::to_string_pretty(&manifest).map_err(|e| format!("{}", e))?;
+        write(&self.dir.join("manifest.json"), s.as_bytes())?;
+        Ok(manifest)
+    }
+
+    /// Bytes of a file with `count` profiles besides the profiles.
+    fn overhead(&self, count: usize) -> usize {
+        match (self.options.ndjson, count) {
+            (true, _) => 0,
+            (false, 0) => 2,
+            (false, n) => 4 + 2 * (n - 1),
+        }
+    }
+
+    fn open(&mut self) -> Result<(), String> {
+        let path = self.dir.join(format!(".part-{}", self.files.len()));
+        let file = BufWriter::new(File::create(&path).map_err(|e| format!("{}", e))?);
+        let out: Box<dyn Write> = if self.options.gzip {
+            Box::new(GzEncoder::new(file, Compression::default()))
+        } else {
+            Box::new(file)
+        };
+        self.part = Some(Part {
+            path,
+            out,
+            user_ids: vec![],
+            bytes: 0,
+        });
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), String> {
+        let Part {
+            path,
+            mut out,
+            user_ids,
+            ..
+        } = match self.part.take() {
+            Some(part) => part,
+            None => return Ok(()),
+        };
+        let end = match (self.options.ndjson, user_ids.len()) {
+            (true, _) => "",
+            (false, 0) => "[]",
+            (false, _) => "\n]",
+        };
+        out.write_all(end.as_bytes())
+            .and_then(|_| out.flush())
+            .map_err(|e| format!("{}", e))?;
+        // finishes the gzip stream
+        drop(out);
+        let buf = fs::read(&path).map_err(|e| format!("{}", e))?;
+        let sha256 = sha256_hex(&buf);
+        let stem = match self.options.naming {
+            FileNaming::Index => self.files.len().to_string(),
+            FileNaming::Hash => sha256.clone(),
+            FileNaming::Range => match (user_ids.first(), user_ids.last()) {
+                (Some(first), Some(last)) => {
+                    format!("{}-{}--{}", self.files.len(), safe(first), safe(last))
+                }
+                _ => format!("{}-empty", self.files.len()),
+            },
+        };
+        let name = format!(
+            "{}.{}{}",
+            stem,
+            if self.options.ndjson {
+                "ndjson"
+            } else {
+                "json"
+            },
+            if self.options.gzip { ".gz" } else { "" }
+        );
+        fs::rename(&path, self.dir.join(&name)).map_err(|e| format!("{}", e))?;
+        self.files.push(json!({
+            "file": name,
+            "count": user_ids.len(),
+            "user_ids": user_ids,
+            "bytes": buf.len(),
+            "sha256": sha256,
+        }));
+        Ok(())
+    }
+}
+
+/// The first `MAX_ID_LEN` characters of `s` with everything but ASCII
+/// letters, digits, `-`, `_` and `.` replaced by `_`, for file names.
+fn safe(s: &str) -> String {
+    s.chars()
+        .take(MAX_ID_LEN)
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use loader::load_profiles;
+
+    fn profile(user_id: &str) -> Value {
+        json!({ "user_id": { "value": user_id }, "x": [1, 2] })
+    }
+
+    #[test]
+    fn test_split() {
+        let dir = std::env::temp_dir().join(format!("v2conv-split-{}", std::process::id()));
+        let dir = dir.to_str().unwrap();
+        let profiles: Vec<Value> = ["ad|a", "ad|b", "ad|c"]
+            .iter()
+            .map(|u| profile(u))
+            .collect();
+        let one = serde_json::to_string_pretty(&profiles[..1]).unwrap().len();
+
+        // room for exactly one profile per file
+        let mut writer = SplitWriter::new(
+            dir,
+            SplitOptions {
+                bytes: Some(one),
+                naming: FileNaming::Range,
+                ..SplitOptions::default()
+            },
+        )
+        .unwrap();
+        for p in &profiles {
+            writer.write(p).unwrap();
+        }
+        let manifest = writer.finish().unwrap();
+        assert_eq!(manifest["count"], json!(3));
+        assert_eq!(manifest["files"][0]["file"], json!("0-ad_a--ad_a.json"));
+        let s = fs::read_to_string(format!("{}/1-ad_b--ad_b.json", dir)).unwrap();
+        assert_eq!(s, serde_json::to_string_pretty(&profiles[1..2]).unwrap());
+        assert_eq!(
+            manifest["files"][1]["sha256"],
+            json!(sha256_hex(s.as_bytes()))
+        );
+        fs::remove_dir_all(dir).unwrap();
+
+        let mut writer = SplitWriter::new(
+            dir,
+            SplitOptions {
+                profiles: Some(2),
+                naming: FileNaming::Hash,
+                gzip: true,
+                ndjson: true,
+                ..SplitOptions::default()
+            },
+        )
+        .unwrap();
+        for p in &profiles {
+            writer.write(p).unwrap();
+        }
+        let manifest = writer.finish().unwrap();
+        assert_eq!(manifest["files"][0]["user_ids"], json!(["ad|a", "ad|b"]));
+        let file = manifest["files"][0]["file"].as_str().unwrap();
+        assert!(file.ends_with(".ndjson.gz"));
+        let mut lines = String::new();
+        GzDecoder::new(File::open(format!("{}/{}", dir, file)).unwrap())
+            .read_to_string(&mut lines)
+            .unwrap();
+        assert_eq!(lines.lines().count(), 2);
+        assert_eq!(
+            serde_json::from_str::<Value>(lines.lines().next().unwrap()).unwrap(),
+            profiles[0]
+        );
+        // read back without the manifest and files of earlier runs
+        fs::write(format!("{}/stale.json", dir), "[{}]").unwrap();
+        assert_eq!(load_profiles(dir).unwrap().len(), 3);
+        fs::remove_dir_all(dir).unwrap();
+
+        // only the index keeps these apart
+        assert_eq!(safe("ad|a"), safe("ad/a"));
+        assert_eq!(safe(&"a".repeat(100)).len(), MAX_ID_LEN);
+    }
+}
@@ -0,0 +1,205 @@
+use std::env;
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+use serde_json::Value;
+
+use attributes::{from_value, payload, to_value, walk_mut};
+use schema::Profile;
+
+pub fn format(dt: &DateTime<Utc>) -> String {
+    dt.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Current time as RFC 3339 or the pinned one if given.
+pub fn now(pinned: Option<&str>) -> Result<String, String> {
+    match pinned {
+        Some(s) => parse(s).ok_or_else(|| format!("invalid timestamp: {}", s)),
+        None => Ok(format(&Utc::now())),
+    }
+}
+
+/// The time pinned by the `SOURCE_DATE_EPOCH` environment variable (seconds
+/// since the epoch), as used for reproducible builds.
+pub fn source_date_epoch() -> Result<Option<String>, String> {
+    match env::var("SOURCE_DATE_EPOCH") {
+        Ok(s) => s
+            .trim()
+            .parse()
+            .ok()
+            .and_then(|secs| Utc.timestamp_opt(secs, 0).single())
+            .map(|dt| Some(format(&dt)))
+            .ok_or_else(|| format!("invalid SOURCE_DATE_EPOCH: {}", s)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Parses the date formats found in HRIS and Mozillians dumps into RFC 3339.
+pub fn parse(s: &str) -> Option<String> {
+    if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
+        return Some(format(&dt.with_timezone(&Utc)));
+    }
+    for f in &["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"] {
+        if let Ok(dt) = NaiveDateTime::parse_from_str(s, f) {
+            return Some(format(&DateTime::from_utc(dt, Utc)));
+        }
+    }
+    for f in &["%Y-%m-%d", "%m/%d/%Y"] {
+        if let Ok(d) = NaiveDate::parse_from_str(s, f) {
+            return Some(format(&DateTime::from_utc(d.and_hms(0, 0, 0), Utc)));
+        }
+    }
+    None
+}
+
+/// Creation date of a profile: the HRIS hire date or the date the user
+/// joined mozillians.org.
+pub fn created(hris: &Value, mozillians: &Value) -> Option<String> {
+    hris["Hire_Date"]
+        .as_str()
+        .and_then(parse)
+        .or_else(|| mozillians["date_joined"].as_str().and_then(parse))
+}
+
+/// Sets `last_modified` on the attributes at `paths`.
+pub fn touch(v: &mut Value, paths: &[String], now: &str) {
+    for path in paths {
+        if let Some(a) = v.pointer_mut(path) {
+            a["metadata"]["last_modified"] = json!(now);
+        }
+    }
+}
+
+/// Fills in the remaining timestamps once all sources are merged.
+pub fn finish(p: Profile, created: &str, now: &str) -> Result<Profile, String> {
+    let mut p = p;
+    p.created.value = Some(String::from(created));
+    p.last_modified.value = Some(String::from(now));
+    p.last_modified.metadata.last_modified = String::from(now);
+    let mut v = to_value(&p)?;
+    walk_mut(&mut v, &mut |_, a| {
+        let metadata = &mut a["metadata"];
+        if metadata["created"]
+            .as_str()
+            .map(str::is_empty)
+            .unwrap_or(true)
+        {
+            metadata["created"] = json!(created);
+        }
+        if metadata["last_modified"]
+            .as_str()
+            .map(str::is_empty)
+            .unwrap_or(true)
+        {
+            metadata["last_modified"] = json!(created);
+        }
+    });
+    from_value(v)
+}
+
+/// Keeps the timestamps of the attributes whose payload is the same as in
+/// `previous`, this profile in an earlier output, so merging unchanged
+/// sources again does not move them. The profile keeps its earlier
+/// `last_modified` if none of its attributes changed.
+pub fn carry(p: Profile, previous: &Value) -> Result<Profile, String> {
+    let mut v = to_value(&p)?;
+    let mut changed = false;
+    walk_mut(&mut v, &mut |path, a| {
+        if path == "/last_modified" {
+            return;
+        }
+        match previous.pointer(path) {
+            Some(b) if payload(b) == payload(a) => {
+                for k in &["created", "last_modified"] {
+                    if b["metadata"][k].is_string() {
+                        a["metadata"][k] = b["metadata"][k].clone();
+                    }
+                }
+            }
+            _ => changed = true,
+        }
+    });
+    let earlier = &previous["last_modified"];
+    if !changed && earlier["value"].is_string() {
+        v["last_modified"]["value"] = earlier["value"].clone();
+        v["last_modified"]["metadata"]["last_modified"] = earlier["value"].clone();
+    }
+    from_value(v)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use attributes::changed;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse("2016-04-04"),
+            Some(String::from("2016-04-04T00:00:00Z"))
+        );
+        assert_eq!(
+            parse("2012-05-09T12:34:56.123456"),
+            Some(String::from("2012-05-09T12:34:56Z"))
+        );
+        assert_eq!(
+            parse("2012-05-09T12:34:56+02:00"),
+            Some(String::from("2012-05-09T10:34:56Z"))
+        );
+        assert_eq!(parse("yesterday"), None);
+
+        // the only test touching SOURCE_DATE_EPOCH
+        env::set_var("SOURCE_DATE_EPOCH", "1541030400");
+        assert_eq!(
+            source_date_epoch(),
+            Ok(Some(String::from("2018-11-01T00:00:00Z")))
+        );
+        env::set_var("SOURCE_DATE_EPOCH", "99999999999999999");
+        assert!(source_date_epoch().is_err());
+        env::set_var("SOURCE_DATE_EPOCH", "soon");
+        assert!(source_date_epoch().is_err());
+        env::remove_var("SOURCE_DATE_EPOCH");
+        assert_eq!(source_date_epoch(), Ok(None));
+    }
+
+    #[test]
+    fn test_touch_and_finish() {
+        let before = to_value(&Profile::default()).unwrap();
+        let mut p = Profile::default();
+        p.first_name.value = Some(String::from("Hans"));
+        let mut v = to_value(&p).unwrap();
+        let paths = changed(&before, &v);
+        touch(&mut v, &paths, "2018-11-01T00:00:00Z");
+        let p = from_value(v).unwrap();
+        let p = finish(p, "2016-04-04T00:00:00Z", "2018-11-01T00:00:00Z").unwrap();
+        assert_eq!(p.first_name.metadata.last_modified, "2018-11-01T00:00:00Z");
+        assert_eq!(p.first_name.metadata.created, "2016-04-04T00:00:00Z");
+        assert_eq!(p.last_name.metadata.last_modified, "2016-04-04T00:00:00Z");
+        assert_eq!(p.created.value, Some(String::from("2016-04-04T00:00:00Z")));
+    }
+
+    #[test]
+    fn test_carry() {
+        // what merging gives for a profile with a first name at `now`
+        let merged = |last_name: Option<&str>, now| {
+            let mut p = Profile::default();
+            p.first_name.value = Some(String::from("Hans"));
+            p.last_name.value = last_name.map(String::from);
+            let mut v = to_value(&p).unwrap();
+            let paths = changed(&to_value(&Profile::default()).unwrap(), &v);
+            touch(&mut v, &paths, now);
+            finish(from_value(v).unwrap(), "2016-04-04T00:00:00Z", now).unwrap()
+        };
+        let previous = to_value(&merged(None, "2018-11-01T00:00:00Z")).unwrap();
+
+        let unchanged = carry(merged(None, "2019-01-01T00:00:00Z"), &previous).unwrap();
+        assert_eq!(to_value(&unchanged).unwrap(), previous);
+
+        let p = carry(merged(Some("Wurst"), "2019-01-01T00:00:00Z"), &previous).unwrap();
+        assert_eq!(
+            p.last_modified.value,
+            Some(String::from("2019-01-01T00:00:00Z"))
+        );
+        assert_eq!(p.last_name.metadata.last_modified, "2019-01-01T00:00:00Z");
+        assert_eq!(p.first_name.metadata.last_modified, "2018-11-01T00:00:00Z");
+    }
+}
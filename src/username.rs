@@ -1,8 +1,619 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+
 use base64::{encode_config, URL_SAFE};
-use uuid::Uuid;
+use regex::Regex;
+use serde_json::{Map, Value};
+
+use jws::{read_secret, Key};
+use loader::load_json;
+use schema::{Profile, PublisherAuthority};
+
+/// Environment variable holding the username key if no key file is given.
+pub const USERNAME_KEY_ENV: &str = "V2CONV_USERNAME_KEY";
+
+/// Secret key for the generated `r--` usernames.
+pub struct UsernameKey {
+    key: Key,
+}
+
+impl UsernameKey {
+    pub fn new(secret: &[u8]) -> Self {
+        UsernameKey {
+            key: Key::Hmac(secret.to_vec()),
+        }
+    }
+
+    /// Reads the key from `path` or, if not given, from `V2CONV_USERNAME_KEY`.
+    /// A trailing newline is not part of the key.
+    pub fn load(path: Option<&str>) -> Result<Self, String> {
+        read_secret(path, USERNAME_KEY_ENV).map(|secret| UsernameKey::new(&secret))
+    }
+}
+
+/// `r--` followed by the first 16 bytes of the HMAC-SHA256 of `value`.
+pub fn generate_username(value: &str, key: &UsernameKey) -> String {
+    let mac = key.key.sign(value.as_bytes()).expect("hmac");
+    format!("r--{}", encode_config(&mac[..16], URL_SAFE))
+}
+
+/// Finds a handle for one service in the free-form LDAP `usernames` values.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Extractor {
+    /// Key of the handle in `Profile.usernames`.
+    pub key: String,
+    /// The first capture group is the handle.
+    pub pattern: String,
+    #[serde(default)]
+    pub lowercase: bool,
+}
+
+/// The extractors applied to LDAP usernames and which of them supplies the
+/// `mozilliansorg` username.
+///
+/// Loaded from a JSON file like
+/// `{ "extractors": [{ "key": "irc", "pattern": "(?i)^irc[:\\s]+(\\S+)$" }], "primary": "irc" }`.
+/// Without a `primary` every staff member gets a generated username.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Extractors {
+    extractors: Vec<Extractor>,
+    #[serde(default)]
+    primary: Option<String>,
+    #[serde(skip)]
+    compiled: Vec<Regex>,
+}
+
+const DEFAULT_EXTRACTORS: &[(&str, &str, bool)] = &[
+    ("irc", r"(?i)^irc[:\s]+(?:.*\s)?(\S+)\s*$", false),
+    ("github", r"(?i)^github[:\s]+@?(\S+)\s*$", true),
+    ("slack", r"(?i)^slack[:\s]+@?(\S+)\s*$", true),
+    ("matrix", r"(?i)^matrix[:\s]+(@\S+:\S+)\s*$", false),
+];
+
+impl Default for Extractors {
+    fn default() -> Self {
+        let extractors = DEFAULT_EXTRACTORS
+            .iter()
+            .map(|(key, pattern, lowercase)| Extractor {
+                key: String::from(*key),
+                pattern: String::from(*pattern),
+                lowercase: *lowercase,
+            })
+            .collect();
+        Extractors::new(extractors, Some(String::from("irc"))).expect("default extractors")
+    }
+}
+
+impl Extractors {
+    pub fn new(extractors: Vec<Extractor>, primary: Option<String>) -> Result<Self, String> {
+        let compiled = extractors
+            .iter()
+            .map(|e| Regex::new(&e.pattern).map_err(|err| format!("{}: {}", e.key, err)))
+            .collect::<Result<_, _>>()?;
+        if let Some(primary) = &primary {
+            if !extractors.iter().any(|e| &e.key == primary) {
+                return Err(format!("no extractor for the primary username {}", primary));
+            }
+        }
+        Ok(Extractors {
+            extractors,
+            primary,
+            compiled,
+        })
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let config: Extractors =
+            serde_json::from_value(load_json(path)?).map_err(|e| format!("{}: {}", path, e))?;
+        Extractors::new(config.extractors, config.primary).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// Replaces the values an extractor matches with the handle under the
+    /// extractor's key. Values nothing matches are kept as they are, and so
+    /// are further handles for a key that already has one, which are reported
+    /// as belonging to `user` if `report` is set.
+    pub fn extract(
+        &self,
+        values: &Map<String, Value>,
+        user: &str,
+        report: bool,
+    ) -> Map<String, Value> {
+        let mut usernames = Map::new();
+        for (k, v) in values {
+            let found = v.as_str().and_then(|s| {
+                self.extractors
+                    .iter()
+                    .zip(&self.compiled)
+                    .find_map(|(e, re)| re.captures(s).map(|c| (e, c)))
+                    .and_then(|(e, c)| c.get(1).map(|m| (e, m.as_str())))
+            });
+            match found {
+                Some((e, handle)) => {
+                    let handle = if e.lowercase {
+                        handle.to_lowercase()
+                    } else {
+                        String::from(handle)
+                    };
+                    if let Some(first) = usernames.get(&e.key) {
+                        if report {
+                            eprintln!(
+                                "{} of {} has a second {} handle {:?} besides {}, keeping it as {}",
+                                k, user, e.key, handle, first, k
+                            );
+                        }
+                        usernames.insert(k.clone(), v.clone());
+                    } else {
+                        usernames.insert(e.key.clone(), json!(handle));
+                    }
+                }
+                None => {
+                    usernames.insert(k.clone(), v.clone());
+                }
+            }
+        }
+        usernames
+    }
+
+    /// The handle of the primary service among extracted `usernames`.
+    pub fn primary(&self, usernames: &Map<String, Value>) -> Option<String> {
+        self.primary
+            .as_ref()
+            .and_then(|key| usernames.get(key))
+            .and_then(Value::as_str)
+            .map(String::from)
+    }
+}
+
+/// Rules for usernames taken from IRC, LDAP or mozillians.org.
+///
+/// Loaded from a JSON file like
+/// `{ "allowed": "abcdefghijklmnopqrstuvwxyz0123456789-_.", "min_length": 2, "max_length": 32,
+/// "reserved": ["admin"] }`. Missing fields keep their defaults.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct UsernamePolicy {
+    allowed: String,
+    min_length: usize,
+    max_length: usize,
+    reserved: BTreeSet<String>,
+}
+
+const RESERVED: &[&str] = &[
+    "abuse",
+    "admin",
+    "administrator",
+    "api",
+    "help",
+    "info",
+    "me",
+    "mozilla",
+    "noreply",
+    "null",
+    "postmaster",
+    "root",
+    "security",
+    "staff",
+    "support",
+    "system",
+    "undefined",
+    "webmaster",
+];
+
+impl Default for UsernamePolicy {
+    fn default() -> Self {
+        UsernamePolicy {
+            allowed: String::from("abcdefghijklmnopqrstuvwxyz0123456789-_."),
+            min_length: 2,
+            max_length: 32,
+            reserved: RESERVED.iter().map(|r| String::from(*r)).collect(),
+        }
+    }
+}
+
+impl UsernamePolicy {
+    pub fn load(path: &str) -> Result<Self, String> {
+        serde_json::from_value(load_json(path)?).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// Why `username` violates the policy, if it does.
+    pub fn check(&self, username: &str) -> Result<(), String> {
+        let len = username.chars().count();
+        if len < self.min_length || len > self.max_length {
+            return Err(format!(
+                "length {} not between {} and {}",
+                len, self.min_length, self.max_length
+            ));
+        }
+        if let Some(c) = username.chars().find(|c| !self.allowed.contains(*c)) {
+            return Err(format!("character {:?} not allowed", c));
+        }
+        if is_generated(username) {
+            return Err(String::from("r-- is reserved for generated usernames"));
+        }
+        if self.reserved.contains(&username.to_lowercase()) {
+            return Err(String::from("reserved"));
+        }
+        Ok(())
+    }
+}
+
+/// Everything needed to pick the `mozilliansorg` username of a profile.
+pub struct Usernames {
+    pub key: UsernameKey,
+    pub extractors: Extractors,
+    pub policy: UsernamePolicy,
+    /// Whether mozillians.org usernames, rejected usernames and further
+    /// handles are reported, off when the same sources are mapped again.
+    pub report: bool,
+}
+
+impl Usernames {
+    /// `candidate` if it follows the policy. Rejected usernames of `user` are
+    /// reported.
+    pub fn valid(&self, candidate: Option<String>, user: &str) -> Option<String> {
+        candidate.filter(|u| match self.policy.check(u) {
+            Ok(()) => true,
+            Err(e) => {
+                if self.report {
+                    eprintln!("rejected username {:?} of {}: {}", u, user, e);
+                }
+                false
+            }
+        })
+    }
+
+    /// `candidate` if it follows the policy, otherwise the generated
+    /// username for `seed`.
+    pub fn pick(&self, candidate: Option<String>, seed: &str) -> String {
+        self.valid(candidate, seed)
+            .unwrap_or_else(|| generate_username(seed, &self.key))
+    }
+}
+
+/// A username taken away from a profile because another one already had it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rename {
+    pub user_id: String,
+    pub from: String,
+    pub to: String,
+}
+
+/// What `keep_generated` and `dedupe` need to know of a profile, so the
+/// usernames can be settled without holding on to the profiles.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Claim {
+    pub user_id: Option<String>,
+    pub primary_email: Option<String>,
+    /// The `mozilliansorg` username.
+    pub username: Option<String>,
+    /// Who published the usernames.
+    pub publisher: PublisherAuthority,
+}
+
+impl Claim {
+    pub fn new(p: &Profile) -> Self {
+        Claim {
+            user_id: p.user_id.value.clone(),
+            primary_email: p.primary_email.value.clone(),
+            username: p
+                .usernames
+                .values
+                .get("mozilliansorg")
+                .and_then(Value::as_str)
+                .map(String::from),
+            publisher: p.usernames.signature.publisher.name.clone(),
+        }
+    }
+
+    fn seed(&self) -> String {
+        seed_of(&self.user_id, &self.primary_email)
+    }
+
+    fn lowercase(&self) -> Option<String> {
+        self.username.as_ref().map(|u| u.to_lowercase())
+    }
+}
+
+/// What the generated username of `p` derives from: its user id, else its
+/// primary email.
+pub fn seed(p: &Profile) -> String {
+    seed_of(&p.user_id.value, &p.primary_email.value)
+}
+
+fn seed_of(user_id: &Option<String>, primary_email: &Option<String>) -> String {
+    user_id
+        .clone()
+        .or_else(|| primary_email.clone())
+        .unwrap_or_default()
+}
+
+fn is_generated(username: &str) -> bool {
+    username.starts_with("r--")
+}
+
+/// Restores the generated usernames users had in `previous` output, so
+/// rotating the username key only affects new users. Usernames taken from
+/// IRC or mozillians.org are left alone. Returns the number of restored
+/// usernames.
+pub fn keep_generated(claims: &mut [Claim], previous: &[Value]) -> usize {
+    let old: BTreeMap<&str, &str> = previous
+        .iter()
+        .filter_map(|p| {
+            let username = p["usernames"]["values"]["mozilliansorg"].as_str()?;
+            let user_id = p["user_id"]["value"].as_str()?;
+            if is_generated(username) {
+                Some((user_id, username))
+            } else {
+                None
+            }
+        })
+        .collect();
+    let mut kept = 0;
+    for c in claims.iter_mut() {
+        let restore = match (&c.username, &c.user_id) {
+            (Some(current), Some(id)) if is_generated(current) => old
+                .get(id.as_str())
+                .filter(|old| **old != current)
+                .map(|old| String::from(*old)),
+            _ => None,
+        };
+        if restore.is_some() {
+            c.username = restore;
+            kept += 1;
+        }
+    }
+    kept
+}
+
+/// A profile whose username is still taken after `dedupe`, for instance
+/// because it has no user id and email to generate another one from or
+/// shares its user id with another profile.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Clash {
+    /// Index of the profile in the profiles given to `dedupe`.
+    pub index: usize,
+    pub user_id: String,
+    pub username: String,
+    /// User id of the profile keeping the username.
+    pub other: String,
+}
+
+/// Makes the `mozilliansorg` usernames of `claims` unique, ignoring case.
+///
+/// Of the profiles sharing a username, the one whose username was published
+/// by mozillians.org keeps it, then the one with the smallest user id. The
+/// others fall back to the generated `r--` username of their user id, so the
+/// outcome neither depends on the order of `claims` nor changes between
+/// runs. Profiles that still share a username are returned as clashes, in
+/// order, for the caller to drop.
+pub fn dedupe(claims: &mut [Claim], key: &UsernameKey) -> (Vec<Rename>, Vec<Clash>) {
+    let mut taken: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    for (i, c) in claims.iter().enumerate() {
+        if let Some(u) = c.lowercase() {
+            taken.entry(u).or_default().push(i);
+        }
+    }
+    let mut renames = vec![];
+    let mut unresolved = BTreeSet::new();
+    for (_, mut claimants) in taken.into_iter().filter(|(_, c)| c.len() > 1) {
+        claimants.sort_by_key(|i| {
+            let c = &claims[*i];
+            (c.publisher != PublisherAuthority::Mozilliansorg, c.seed())
+        });
+        for i in claimants.into_iter().skip(1) {
+            let c = &mut claims[i];
+            let id = c.seed();
+            if id.is_empty() {
+                unresolved.insert(i);
+                continue;
+            }
+            let to = generate_username(&id, key);
+            let from = c.username.replace(to.clone());
+            renames.push(Rename {
+                user_id: id,
+                from: from.unwrap_or_default(),
+                to,
+            });
+        }
+    }
+    let mut seen = BTreeMap::new();
+    for (i, c) in claims.iter().enumerate() {
+        if let Some(u) = c.lowercase() {
+            if unresolved.contains(&i) || seen.contains_key(&u) {
+                unresolved.insert(i);
+            } else {
+                seen.insert(u, c.seed());
+            }
+        }
+    }
+    let clashes = unresolved
+        .into_iter()
+        .filter_map(|i| {
+            let c = &claims[i];
+            let u = c.username.as_ref()?;
+            Some(Clash {
+                index: i,
+                user_id: c.seed(),
+                username: u.clone(),
+                other: seen.get(&u.to_lowercase()).cloned().unwrap_or_default(),
+            })
+        })
+        .collect();
+    (renames, clashes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn claim(user_id: &str, username: &str, publisher: PublisherAuthority) -> Claim {
+        let mut p = Profile::default();
+        p.user_id.value = Some(String::from(user_id));
+        p.usernames
+            .values
+            .insert(String::from("mozilliansorg"), username.into());
+        p.usernames.signature.publisher.name = publisher;
+        Claim::new(&p)
+    }
+
+    #[test]
+    fn test_extract() {
+        let extractors = Extractors::default();
+        let values = json!({
+            "LDAP-1": "IRC: hansw",
+            "LDAP-2": "GITHUB: @HansW",
+            "LDAP-3": "Slack hans",
+            "LDAP-4": "matrix: @hans:mozilla.org",
+            "LDAP-5": "irc: other",
+            "LDAP-6": "ICQ: 1234",
+        });
+        let usernames = extractors.extract(values.as_object().unwrap(), "hans", true);
+        assert_eq!(
+            json!(usernames),
+            json!({
+                "irc": "hansw",
+                "github": "hansw",
+                "slack": "hans",
+                "matrix": "@hans:mozilla.org",
+                "LDAP-5": "irc: other",
+                "LDAP-6": "ICQ: 1234",
+            })
+        );
+        assert_eq!(extractors.primary(&usernames), Some(String::from("hansw")));
+
+        let github = Extractors::new(
+            vec![Extractor {
+                key: String::from("github"),
+                pattern: String::from(r"(?i)^github[:\s]+@?(\S+)$"),
+                lowercase: false,
+            }],
+            Some(String::from("github")),
+        )
+        .unwrap();
+        let usernames = github.extract(values.as_object().unwrap(), "hans", true);
+        assert_eq!(github.primary(&usernames), Some(String::from("HansW")));
+        assert!(Extractors::new(vec![], Some(String::from("irc"))).is_err());
+    }
+
+    #[test]
+    fn test_policy() {
+        let policy = UsernamePolicy::default();
+        assert_eq!(policy.check("hans.w-2_x"), Ok(()));
+        assert!(policy.check("Volunteer X").is_err());
+        assert!(policy.check("HansW").is_err());
+        assert!(policy.check("h").is_err());
+        assert!(policy.check(&"h".repeat(33)).is_err());
+        assert!(policy.check("admin").is_err());
+        assert!(policy.check("r--abc").is_err());
+
+        let policy: UsernamePolicy = serde_json::from_value(json!({
+            "allowed": "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ",
+            "reserved": [],
+        }))
+        .unwrap();
+        assert_eq!(policy.check("HansW"), Ok(()));
+        assert_eq!(policy.check("admin"), Ok(()));
+        assert!(policy.check("hans.w").is_err());
+
+        let usernames = Usernames {
+            key: UsernameKey::new(b"x"),
+            extractors: Extractors::default(),
+            policy: UsernamePolicy::default(),
+            report: true,
+        };
+        assert_eq!(
+            usernames.pick(Some(String::from("hansw")), "hans@mozilla.com"),
+            "hansw"
+        );
+        assert_eq!(
+            usernames.pick(Some(String::from("Hans W")), "hans@mozilla.com"),
+            generate_username("hans@mozilla.com", &usernames.key)
+        );
+    }
+
+    #[test]
+    fn test_generate_username() {
+        let old = UsernameKey::new(b"old");
+        let new = UsernameKey::new(b"new");
+        let u = generate_username("hans@mozilla.com", &old);
+        assert_eq!(u, generate_username("hans@mozilla.com", &old));
+        assert_ne!(u, generate_username("hans@mozilla.com", &new));
+        assert_eq!(u.len(), 27);
+
+        let previous = vec![json!({
+            "user_id": { "value": "ad|Mozilla-LDAP|hans" },
+            "usernames": { "values": { "mozilliansorg": u } }
+        })];
+        let mut claims = vec![
+            claim(
+                "ad|Mozilla-LDAP|hans",
+                &generate_username("hans@mozilla.com", &new),
+                PublisherAuthority::Ldap,
+            ),
+            claim(
+                "ad|Mozilla-LDAP|fritz",
+                &generate_username("fritz@mozilla.com", &new),
+                PublisherAuthority::Ldap,
+            ),
+        ];
+        assert_eq!(keep_generated(&mut claims, &previous), 1);
+        assert_eq!(claims[0].username, Some(u));
+    }
+
+    #[test]
+    fn test_dedupe() {
+        let mut claims = vec![
+            claim("ad|Mozilla-LDAP|b", "hans", PublisherAuthority::Ldap),
+            claim("ad|Mozilla-LDAP|a", "Hans", PublisherAuthority::Ldap),
+            claim("github|1", "hans", PublisherAuthority::Mozilliansorg),
+            claim("ad|Mozilla-LDAP|c", "fritz", PublisherAuthority::Ldap),
+        ];
+        let key = UsernameKey::new(b"x");
+        let (renames, clashes) = dedupe(&mut claims, &key);
+        assert!(clashes.is_empty());
+        assert_eq!(renames.len(), 2);
+        assert_eq!(renames[0].user_id, "ad|Mozilla-LDAP|a");
+        assert_eq!(renames[1].user_id, "ad|Mozilla-LDAP|b");
+        assert_eq!(claims[2].username, Some(String::from("hans")));
+        assert_eq!(
+            claims[0].username,
+            Some(generate_username("ad|Mozilla-LDAP|b", &key))
+        );
+
+        let mut reversed: Vec<Claim> = vec![
+            claim("ad|Mozilla-LDAP|c", "fritz", PublisherAuthority::Ldap),
+            claim("github|1", "hans", PublisherAuthority::Mozilliansorg),
+            claim("ad|Mozilla-LDAP|a", "Hans", PublisherAuthority::Ldap),
+            claim("ad|Mozilla-LDAP|b", "hans", PublisherAuthority::Ldap),
+        ];
+        assert_eq!(dedupe(&mut reversed, &key), (renames, vec![]));
 
-pub fn generate_username(value: &str, entropy: &str) -> String {
-    let id = Uuid::new_v5(&Uuid::NAMESPACE_URL, format!("{}#{}", value, entropy).as_bytes());
-    let username = encode_config(id.as_bytes(), URL_SAFE);
-    format!("r--{}", username)
+        // nothing to generate a username from and a shared user id
+        let mut claims = vec![
+            claim("", "hans", PublisherAuthority::Mozilliansorg),
+            claim("", "hans", PublisherAuthority::Mozilliansorg),
+            claim("github|1", "fritz", PublisherAuthority::Mozilliansorg),
+            claim("github|1", "fritz", PublisherAuthority::Mozilliansorg),
+            claim("github|1", "fritz", PublisherAuthority::Mozilliansorg),
+        ];
+        let (renames, clashes) = dedupe(&mut claims, &key);
+        assert_eq!(renames.len(), 2);
+        assert_eq!(
+            clashes,
+            vec![
+                Clash {
+                    index: 1,
+                    user_id: String::new(),
+                    username: String::from("hans"),
+                    other: String::new(),
+                },
+                Clash {
+                    index: 4,
+                    user_id: String::from("github|1"),
+                    username: renames[0].to.clone(),
+                    other: String::from("github|1"),
+                },
+            ]
+        );
+    }
 }
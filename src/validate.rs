@@ -0,0 +1,188 @@
+use chrono::DateTime;
+use serde_json::Value;
+
+use attributes::{to_value, user};
+use schema::Profile;
+
+const SCHEMA: &str = include_str!("schema.json");
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Violation {
+    pub pointer: String,
+    pub message: String,
+}
+
+/// Validates against a draft-04 JSON schema.
+///
+/// Only the keywords used by the bundled `schema.json` are supported:
+/// `$ref` (local), `allOf`, `type`, `enum`, `properties`, `required`,
+/// `additionalProperties`, `items` and the `date-time` format.
+pub struct Validator {
+    schema: Value,
+}
+
+impl Validator {
+    pub fn bundled() -> Self {
+        Validator {
+            schema: serde_json::from_str(SCHEMA).expect("invalid bundled schema.json"),
+        }
+    }
+
+    pub fn validate(&self, v: &Value) -> Vec<Violation> {
+        let mut violations = vec![];
+        self.check(&self.schema, v, "", &mut violations);
+        violations
+    }
+
+    /// Resolves a local `$ref` like `#/definitions/Profile`.
+    pub fn resolve<'a>(&'a self, r: &str) -> Option<&'a Value> {
+        if let Some(pointer) = r.strip_prefix('#') {
+            self.schema.pointer(pointer)
+        } else {
+            None
+        }
+    }
+
+    fn check(&self, schema: &Value, v: &Value, pointer: &str, out: &mut Vec<Violation>) {
+        if let Some(r) = schema["$ref"].as_str() {
+            match self.resolve(r) {
+                Some(s) => self.check(s, v, pointer, out),
+                None => violation(out, pointer, format!("unresolvable $ref {}", r)),
+            }
+            return;
+        }
+        if let Some(t) = schema.get("type") {
+            let types: Vec<&str> = match t {
+                Value::Array(a) => a.iter().filter_map(Value::as_str).collect(),
+                t => t.as_str().into_iter().collect(),
+            };
+            if !types.iter().any(|t| has_type(v, t)) {
+                violation(out, pointer, format!("expected type {}, got {}", t, v));
+            }
+        }
+        if let Some(e) = schema["enum"].as_array() {
+            if !e.contains(v) {
+                violation(
+                    out,
+                    pointer,
+                    format!("{} is not one of {}", v, schema["enum"]),
+                );
+            }
+        }
+        if schema["format"].as_str() == Some("date-time") {
+            if let Some(s) = v.as_str() {
+                if DateTime::parse_from_rfc3339(s).is_err() {
+                    violation(out, pointer, format!("{:?} is not a date-time", s));
+                }
+            }
+        }
+        if let Some(o) = v.as_object() {
+            if let Some(required) = schema["required"].as_array() {
+                for r in required.iter().filter_map(Value::as_str) {
+                    if !o.contains_key(r) {
+                        violation(out, pointer, format!("missing required property {}", r));
+                    }
+                }
+            }
+            if schema["additionalProperties"] == json!(false) {
+                for k in o.keys() {
+                    if schema["properties"].get(k).is_none() {
+                        violation(out, pointer, format!("additional property {}", k));
+                    }
+                }
+            }
+        }
+        if let Some(all) = schema["allOf"].as_array() {
+            for s in all {
+                self.check(s, v, pointer, out);
+            }
+        }
+        if let (Some(properties), Some(o)) = (schema["properties"].as_object(), v.as_object()) {
+            for (k, s) in properties {
+                if let Some(v) = o.get(k) {
+                    self.check(s, v, &format!("{}/{}", pointer, k), out);
+                }
+            }
+        }
+        if let (Some(items), Some(a)) = (schema.get("items"), v.as_array()) {
+            for (i, v) in a.iter().enumerate() {
+                self.check(items, v, &format!("{}/{}", pointer, i), out);
+            }
+        }
+    }
+}
+
+/// Validates a generated profile before it is written.
+pub fn check_profile(validator: &Validator, p: Profile) -> Result<Profile, String> {
+    let v = to_value(&p)?;
+    let violations = validator.validate(&v);
+    if violations.is_empty() {
+        Ok(p)
+    } else {
+        Err(violations
+            .iter()
+            .map(|x| format!("{}: {}: {}", user(&v), x.pointer, x.message))
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+}
+
+fn violation(out: &mut Vec<Violation>, pointer: &str, message: String) {
+    out.push(Violation {
+        pointer: String::from(pointer),
+        message,
+    })
+}
+
+fn has_type(v: &Value, t: &str) -> bool {
+    match t {
+        "object" => v.is_object(),
+        "array" => v.is_array(),
+        "string" => v.is_string(),
+        "number" => v.is_number(),
+        "integer" => v.is_i64() || v.is_u64(),
+        "boolean" => v.is_boolean(),
+        "null" => v.is_null(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use timestamp::finish;
+
+    #[test]
+    fn test_validate() {
+        let validator = Validator::bundled();
+        let p = finish(
+            Profile::default(),
+            "2016-04-04T00:00:00Z",
+            "2018-11-01T00:00:00Z",
+        )
+        .unwrap();
+        let mut v = to_value(&p).unwrap();
+        assert_eq!(validator.validate(&v), vec![]);
+
+        v["usernames"]["metadata"]["display"] = json!("staff");
+        v["first_name"]["metadata"]["created"] = json!("");
+        v["foo"] = json!("bar");
+        assert_eq!(
+            validator.validate(&v),
+            vec![
+                Violation {
+                    pointer: String::from(""),
+                    message: String::from("additional property foo"),
+                },
+                Violation {
+                    pointer: String::from("/first_name/metadata/created"),
+                    message: String::from("\"\" is not a date-time"),
+                },
+                Violation {
+                    pointer: String::from("/usernames/metadata/display"),
+                    message: String::from("\"staff\" is not one of [\"public\"]"),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use serde_json::Value;
+
+use attributes::walk;
+use jws::{signing_payload, verify_compact, Keyset, PublicKey};
+use publisher::{parse_publisher, publisher_name};
+
+/// Checks all signatures of a profile and returns the problems found as
+/// `(attribute, problem)`.
+pub fn verify_profile(p: &Value, keys: &Keyset<PublicKey>) -> Vec<(String, String)> {
+    let mut problems = vec![];
+    walk(p, &mut |path, a| {
+        let publisher = &a["signature"]["publisher"];
+        if let Err(e) = verify_signature(a, publisher, keys) {
+            problems.push((format!("{} (publisher)", path), e));
+        }
+        let additional = a["signature"]["additional"]
+            .as_array()
+            .map(|a| a.iter())
+            .unwrap_or_else(|| [].iter());
+        for (i, s) in additional.enumerate() {
+            if let Err(e) = verify_signature(a, s, keys) {
+                problems.push((format!("{} (additional {})", path, i), e));
+            }
+        }
+    });
+    problems
+}
+
+fn verify_signature(
+    attribute: &Value,
+    signature: &Value,
+    keys: &Keyset<PublicKey>,
+) -> Result<(), String> {
+    let jws = signature["value"].as_str().unwrap_or_default();
+    if jws.is_empty() {
+        return Err(String::from("missing signature"));
+    }
+    let name = signature["name"]
+        .as_str()
+        .ok_or_else(|| String::from("signature without publisher"))?;
+    let publisher = parse_publisher(name)?;
+    let key = keys
+        .get(&publisher)
+        .ok_or_else(|| format!("no key for {}", name))?;
+    match verify_compact(key, jws) {
+        Ok(payload) => {
+            if payload == signing_payload(attribute)? {
+                Ok(())
+            } else {
+                Err(String::from("signature does not match attribute"))
+            }
+        }
+        Err(e) => {
+            for (other, k) in keys.iter() {
+                if *other != publisher && verify_compact(k, jws).is_ok() {
+                    return Err(format!(
+                        "signed by {} but attributed to {}",
+                        publisher_name(other),
+                        name
+                    ));
+                }
+            }
+            Err(format!("invalid signature: {}", e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    use attributes::to_value;
+    use jws::{sign_profile, Key};
+    use schema::Profile;
+
+    fn key_specs(dir: &Path) -> Vec<String> {
+        [("cis", "Y2lz"), ("ldap", "bGRhcA")]
+            .iter()
+            .map(|(publisher, secret)| {
+                let path = dir.join(format!("{}.jwk", publisher));
+                fs::write(&path, json!({ "kty": "oct", "k": secret }).to_string()).unwrap();
+                format!("{}={}", publisher, path.to_string_lossy())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_verify_profile() {
+        let dir = ::std::env::temp_dir().join(format!("v2conv-verify-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let specs = key_specs(&dir);
+        let signing = Keyset::load(specs.iter().map(String::as_str), Key::load).unwrap();
+        let verifying = Keyset::load(specs.iter().map(String::as_str), PublicKey::load).unwrap();
+        let p = sign_profile(Profile::default(), &signing).unwrap();
+        let mut v = to_value(&p).unwrap();
+        assert!(verify_profile(&v, &verifying).is_empty());
+
+        v["first_name"]["signature"]["publisher"]["name"] = json!("ldap");
+        v["last_name"]["value"] = json!("Wurst");
+        v["pronouns"]["signature"]["publisher"]["value"] = json!("");
+        let problems = verify_profile(&v, &verifying);
+        assert_eq!(
+            problems,
+            vec![
+                (
+                    String::from("/first_name (publisher)"),
+                    String::from("signed by cis but attributed to ldap")
+                ),
+                (
+                    String::from("/last_name (publisher)"),
+                    String::from("signature does not match attribute")
+                ),
+                (
+                    String::from("/pronouns (publisher)"),
+                    String::from("missing signature")
+                ),
+            ]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,113 @@
+use serde_json::Value;
+
+use attributes::walk;
+use schema::Display;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// Keep hidden attributes but clear their values and signatures.
+    Null,
+    /// Remove hidden attributes from the profile.
+    Drop,
+}
+
+pub fn parse_view(s: &str) -> Result<Display, String> {
+    serde_json::from_value(json!(s)).map_err(|_| format!("unknown display level: {}", s))
+}
+
+/// Whether an audience of `view` may see an attribute with `display`.
+/// Attributes without a display level are never shown.
+pub fn visible(display: &Value, view: &Display) -> bool {
+    serde_json::from_value::<Option<Display>>(display.clone())
+        .ok()
+        .and_then(|d| d)
+        .map(|d| d <= *view)
+        .unwrap_or_default()
+}
+
+/// Restricts a serialized profile to what an audience of `view` may see.
+pub fn filter(v: &mut Value, view: &Display, mode: Mode) {
+    let mut hidden = vec![];
+    walk(v, &mut |path, a| {
+        if !visible(&a["metadata"]["display"], view) {
+            hidden.push(String::from(path));
+        }
+    });
+    for path in hidden {
+        match mode {
+            Mode::Null => {
+                if let Some(a) = v.pointer_mut(&path) {
+                    clear(a);
+                }
+            }
+            Mode::Drop => remove(v, &path),
+        }
+    }
+}
+
+fn clear(a: &mut Value) {
+    if let Some(value) = a.get_mut("value") {
+        *value = match value {
+            Value::Bool(_) => json!(false),
+            _ => Value::Null,
+        };
+    }
+    if let Some(values) = a.get_mut("values") {
+        *values = json!({});
+    }
+    a["signature"]["publisher"]["value"] = json!("");
+    if let Some(additional) = a["signature"]["additional"].as_array_mut() {
+        for s in additional {
+            s["value"] = json!("");
+        }
+    }
+}
+
+/// Removes the attribute at `path` and any group left empty.
+fn remove(v: &mut Value, path: &str) {
+    let mut path = String::from(path);
+    while let Some(i) = path.rfind('/') {
+        let key = path.split_off(i);
+        let parent = match v.pointer_mut(&path) {
+            Some(Value::Object(o)) => o,
+            _ => return,
+        };
+        parent.remove(&key[1..]);
+        if !parent.is_empty() || path.is_empty() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use attributes::to_value;
+    use schema::Profile;
+
+    #[test]
+    fn test_filter() {
+        let mut p = Profile::default();
+        p.first_name.value = Some(String::from("Hans"));
+        p.staff_information.cost_center.value = Some(String::from("1234"));
+        let v = to_value(&p).unwrap();
+
+        let mut public = v.clone();
+        filter(&mut public, &Display::Public, Mode::Null);
+        assert_eq!(public["first_name"]["value"], Value::Null);
+        assert_eq!(public["staff_information"]["cost_center"]["value"], Value::Null);
+        assert_eq!(public["active"]["value"], json!(false));
+
+        let mut staff = v.clone();
+        filter(&mut staff, &Display::Staff, Mode::Drop);
+        assert_eq!(staff["first_name"]["value"], json!("Hans"));
+        assert_eq!(staff["staff_information"]["cost_center"]["value"], json!("1234"));
+        assert!(staff.get("active").is_none());
+        assert!(staff.get("access_information").is_some());
+        assert!(staff["access_information"].get("hris").is_none());
+
+        let mut public = v;
+        filter(&mut public, &Display::Public, Mode::Drop);
+        assert!(public.get("staff_information").is_none());
+    }
+}
@@ -3,6 +3,8 @@ use std::fs::File;
 use std::io::prelude::*;
 use std::path::PathBuf;
 
+use openssl::sha::sha256;
+
 pub fn write(file_name: &PathBuf, content: &[u8]) -> Result<(), String> {
     let mut file = File::create(file_name).map_err(|e| format!("{}", e))?;
     file.write_all(content).map_err(|e| format!("{}", e))
@@ -11,10 +13,15 @@ pub fn write(file_name: &PathBuf, content: &[u8]) -> Result<(), String> {
 pub fn write_enumerated(path: &str, content: &[String]) -> Result<(), String> {
     let p = PathBuf::from(path);
     create_dir_all(&p).map_err(|e| format!("{}", e))?;
-    for (i, c) in content.into_iter().enumerate() {
+    for (i, c) in content.iter().enumerate() {
         let mut file_name = p.clone();
         file_name.push(format!("{}.json", i));
         write(&file_name, c.as_bytes())?;
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Lowercase hex SHA-256 of `data`.
+pub fn sha256_hex(data: &[u8]) -> String {
+    sha256(data).iter().map(|b| format!("{:02x}", b)).collect()
+}